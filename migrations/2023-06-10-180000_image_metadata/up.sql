@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE image_metadata (
+    name TEXT PRIMARY KEY,
+    metadata JSONB NOT NULL,
+    created TIMESTAMP NOT NULL
+);
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE images (
+    id SERIAL PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    original_name TEXT,
+    caption TEXT,
+    uploaded TIMESTAMP NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    hash TEXT NOT NULL
+);
+
+CREATE INDEX images_hash_idx ON images (hash);
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE images ADD COLUMN album TEXT;
+
+CREATE INDEX images_album_idx ON images (album);
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE images ADD COLUMN placeholder TEXT;
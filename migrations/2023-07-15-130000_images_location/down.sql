@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX images_country_idx;
+
+ALTER TABLE images
+    DROP COLUMN latitude,
+    DROP COLUMN longitude,
+    DROP COLUMN country,
+    DROP COLUMN round,
+    DROP COLUMN game;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+ALTER TABLE images
+    ADD COLUMN latitude DOUBLE PRECISION,
+    ADD COLUMN longitude DOUBLE PRECISION,
+    ADD COLUMN country TEXT,
+    ADD COLUMN round INTEGER,
+    ADD COLUMN game TEXT;
+
+CREATE INDEX images_country_idx ON images (country);
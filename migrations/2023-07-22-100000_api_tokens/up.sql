@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE api_tokens (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    hash TEXT NOT NULL UNIQUE,
+    scopes TEXT[] NOT NULL,
+    created TIMESTAMP NOT NULL,
+    expires TIMESTAMP,
+    last_used TIMESTAMP,
+    revoked TIMESTAMP
+);
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE deployments (
+    id SERIAL PRIMARY KEY,
+    started TIMESTAMP NOT NULL,
+    kind TEXT NOT NULL,
+    revision TEXT,
+    output TEXT NOT NULL
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- The original addresses of the hashed visits cannot be restored
+DROP TABLE visitor_salts;
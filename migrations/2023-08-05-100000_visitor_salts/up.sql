@@ -0,0 +1,32 @@
+-- Your SQL goes here
+CREATE TABLE visitor_salts (
+    day DATE PRIMARY KEY,
+    salt TEXT NOT NULL
+);
+
+-- The salt of the latest day is kept, so that the server goes on recognizing the visitors
+-- of that day. Visits are stored in the server's local time, which also picks the salt.
+INSERT INTO visitor_salts (day, salt)
+SELECT
+    max(instance)::date,
+    encode(sha256(uuid_send(gen_random_uuid()) || uuid_send(gen_random_uuid())), 'hex')
+FROM visits
+HAVING count(*) > 0;
+
+-- Hash the addresses of earlier visits with a random salt per day. The salts are
+-- thrown away, so visitors can still be told apart within a day but the addresses
+-- cannot be recovered.
+WITH salts AS (
+    SELECT
+        days.day,
+        COALESCE(
+            visitor_salts.salt,
+            encode(sha256(uuid_send(gen_random_uuid()) || uuid_send(gen_random_uuid())), 'hex')
+        ) AS salt
+    FROM (SELECT DISTINCT instance::date AS day FROM visits) AS days
+    LEFT JOIN visitor_salts ON visitor_salts.day = days.day
+)
+UPDATE visits
+SET visitor = encode(sha256(convert_to(salts.salt || visits.visitor, 'UTF8')), 'hex')
+FROM salts
+WHERE salts.day = visits.instance::date;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE visits
+    DROP COLUMN referrer,
+    DROP COLUMN user_agent,
+    DROP COLUMN status,
+    DROP COLUMN latency_ms;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE visits
+    ADD COLUMN referrer TEXT,
+    ADD COLUMN user_agent TEXT,
+    ADD COLUMN status INTEGER,
+    ADD COLUMN latency_ms INTEGER;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE visits ADD COLUMN is_bot BOOLEAN NOT NULL DEFAULT false;
+
+-- Only the user agents of earlier visits are known, so the other heuristics cannot be applied to them
+UPDATE visits SET is_bot = true
+WHERE user_agent ~* '(bot|crawl|spider|slurp|archiver|facebookexternalhit|embedly|bingpreview|uptime|pingdom|statuscake|monitor|headlesschrome|lighthouse|curl/|wget/|python-|go-http-client|java/|okhttp|libwww-perl|httpclient|axios/|node-fetch|scrapy|feedfetcher|zgrab|masscan|nmap)';
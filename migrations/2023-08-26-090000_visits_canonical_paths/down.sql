@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+-- The original paths of the merged visits are not restored
+ALTER TABLE visits
+    DROP COLUMN utm_source,
+    DROP COLUMN utm_medium,
+    DROP COLUMN utm_campaign;
@@ -0,0 +1,35 @@
+-- Your SQL goes here
+ALTER TABLE visits
+    ADD COLUMN utm_source TEXT,
+    ADD COLUMN utm_medium TEXT,
+    ADD COLUMN utm_campaign TEXT;
+
+-- The values are left percent-encoded, unlike those of new visits
+UPDATE visits SET
+    utm_source = NULLIF(substring(path FROM '[?&]utm_source=([^&]*)'), ''),
+    utm_medium = NULLIF(substring(path FROM '[?&]utm_medium=([^&]*)'), ''),
+    utm_campaign = NULLIF(substring(path FROM '[?&]utm_campaign=([^&]*)'), '')
+WHERE path ~ '[?&]utm_';
+
+-- Rewrites the paths the same way as visitcounter::canonical_path, so that the
+-- visits to the different forms of a path are counted for the same page
+WITH canonical AS (
+    SELECT id,
+        regexp_replace(regexp_replace(split_part(path, '?', 1), '/+$', ''), '\.html$', '') AS page,
+        (
+            SELECT string_agg(param, '&' ORDER BY n)
+            FROM regexp_split_to_table(substr(path, strpos(path, '?') + 1), '&')
+                WITH ORDINALITY AS params (param, n)
+            WHERE strpos(path, '?') > 0
+                AND split_part(param, '=', 1) <> ''
+                AND split_part(param, '=', 1) NOT LIKE 'utm\_%'
+                AND split_part(param, '=', 1) NOT IN ('fbclid', 'gclid', 'dclid', 'gbraid', 'wbraid',
+                    'msclkid', 'yclid', 'twclid', 'ttclid', 'igshid', 'mc_cid', 'mc_eid', '_ga', '_gl', 'ref')
+        ) AS query
+    FROM visits
+)
+UPDATE visits
+SET path = CASE WHEN canonical.page IN ('', '/index') THEN '/' ELSE canonical.page END
+    || COALESCE('?' || canonical.query, '')
+FROM canonical
+WHERE visits.id = canonical.id;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+-- The visits that have been rolled up are not restored
+DROP VIEW site_days;
+DROP VIEW page_days;
+DROP TABLE daily_totals;
+DROP TABLE daily_visits;
@@ -0,0 +1,37 @@
+-- Your SQL goes here
+-- Visits older than the retention period are rolled up into these and deleted
+CREATE TABLE daily_visits (
+    day DATE NOT NULL,
+    path TEXT NOT NULL,
+    is_bot BOOLEAN NOT NULL,
+    views BIGINT NOT NULL,
+    visitors BIGINT NOT NULL,
+    latency_ms_sum BIGINT NOT NULL,
+    latency_count BIGINT NOT NULL,
+    PRIMARY KEY (day, path, is_bot)
+);
+
+-- The visitors of the whole site, which cannot be added up from those of each page
+CREATE TABLE daily_totals (
+    day DATE NOT NULL,
+    is_bot BOOLEAN NOT NULL,
+    views BIGINT NOT NULL,
+    visitors BIGINT NOT NULL,
+    PRIMARY KEY (day, is_bot)
+);
+
+-- The visits that are still kept along with the rolled up ones. Only successful responses
+-- count as views, visits recorded before the status was stored all were.
+CREATE VIEW page_days AS
+    SELECT instance::date AS day, path, is_bot, COUNT(*) AS views,
+        COUNT(DISTINCT visitor) AS visitors, COALESCE(SUM(latency_ms), 0) AS latency_ms_sum,
+        COUNT(latency_ms) AS latency_count
+    FROM visits WHERE status IS NULL OR status BETWEEN 200 AND 299 GROUP BY day, path, is_bot
+    UNION ALL
+    SELECT day, path, is_bot, views, visitors, latency_ms_sum, latency_count FROM daily_visits;
+
+CREATE VIEW site_days AS
+    SELECT instance::date AS day, is_bot, COUNT(*) AS views, COUNT(DISTINCT visitor) AS visitors
+    FROM visits WHERE status IS NULL OR status BETWEEN 200 AND 299 GROUP BY day, is_bot
+    UNION ALL
+    SELECT day, is_bot, views, visitors FROM daily_totals;
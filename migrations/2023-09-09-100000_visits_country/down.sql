@@ -0,0 +1,43 @@
+-- This file should undo anything in `up.sql`
+-- The rolled up visits of the countries of each day are added together
+DROP VIEW site_days;
+DROP VIEW page_days;
+
+CREATE TABLE daily_totals_merged AS
+    SELECT day, is_bot, SUM(views)::bigint AS views, SUM(visitors)::bigint AS visitors
+    FROM daily_totals GROUP BY day, is_bot;
+DROP TABLE daily_totals;
+ALTER TABLE daily_totals_merged RENAME TO daily_totals;
+ALTER TABLE daily_totals
+    ALTER COLUMN views SET NOT NULL,
+    ALTER COLUMN visitors SET NOT NULL,
+    ADD PRIMARY KEY (day, is_bot);
+
+CREATE TABLE daily_visits_merged AS
+    SELECT day, path, is_bot, SUM(views)::bigint AS views, SUM(visitors)::bigint AS visitors,
+        SUM(latency_ms_sum)::bigint AS latency_ms_sum, SUM(latency_count)::bigint AS latency_count
+    FROM daily_visits GROUP BY day, path, is_bot;
+DROP TABLE daily_visits;
+ALTER TABLE daily_visits_merged RENAME TO daily_visits;
+ALTER TABLE daily_visits
+    ALTER COLUMN views SET NOT NULL,
+    ALTER COLUMN visitors SET NOT NULL,
+    ALTER COLUMN latency_ms_sum SET NOT NULL,
+    ALTER COLUMN latency_count SET NOT NULL,
+    ADD PRIMARY KEY (day, path, is_bot);
+
+ALTER TABLE visits DROP COLUMN country;
+
+CREATE VIEW page_days AS
+    SELECT instance::date AS day, path, is_bot, COUNT(*) AS views,
+        COUNT(DISTINCT visitor) AS visitors, COALESCE(SUM(latency_ms), 0) AS latency_ms_sum,
+        COUNT(latency_ms) AS latency_count
+    FROM visits WHERE status IS NULL OR status BETWEEN 200 AND 299 GROUP BY day, path, is_bot
+    UNION ALL
+    SELECT day, path, is_bot, views, visitors, latency_ms_sum, latency_count FROM daily_visits;
+
+CREATE VIEW site_days AS
+    SELECT instance::date AS day, is_bot, COUNT(*) AS views, COUNT(DISTINCT visitor) AS visitors
+    FROM visits WHERE status IS NULL OR status BETWEEN 200 AND 299 GROUP BY day, is_bot
+    UNION ALL
+    SELECT day, is_bot, views, visitors FROM daily_totals;
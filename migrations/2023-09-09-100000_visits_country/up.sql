@@ -0,0 +1,32 @@
+-- Your SQL goes here
+ALTER TABLE visits ADD COLUMN country TEXT;
+
+-- An empty country stands for an unknown one, since primary keys cannot contain nulls.
+-- The visitors of each country can be added up, as a visitor is only in one country a day.
+ALTER TABLE daily_visits
+    ADD COLUMN country TEXT NOT NULL DEFAULT '',
+    DROP CONSTRAINT daily_visits_pkey,
+    ADD PRIMARY KEY (day, path, is_bot, country);
+
+ALTER TABLE daily_totals
+    ADD COLUMN country TEXT NOT NULL DEFAULT '',
+    DROP CONSTRAINT daily_totals_pkey,
+    ADD PRIMARY KEY (day, is_bot, country);
+
+CREATE OR REPLACE VIEW page_days AS
+    SELECT instance::date AS day, path, is_bot, COUNT(*) AS views,
+        COUNT(DISTINCT visitor) AS visitors, COALESCE(SUM(latency_ms), 0) AS latency_ms_sum,
+        COUNT(latency_ms) AS latency_count, COALESCE(country, '') AS country
+    FROM visits WHERE status IS NULL OR status BETWEEN 200 AND 299
+    GROUP BY day, path, is_bot, COALESCE(country, '')
+    UNION ALL
+    SELECT day, path, is_bot, views, visitors, latency_ms_sum, latency_count, country
+    FROM daily_visits;
+
+CREATE OR REPLACE VIEW site_days AS
+    SELECT instance::date AS day, is_bot, COUNT(*) AS views, COUNT(DISTINCT visitor) AS visitors,
+        COALESCE(country, '') AS country
+    FROM visits WHERE status IS NULL OR status BETWEEN 200 AND 299
+    GROUP BY day, is_bot, COALESCE(country, '')
+    UNION ALL
+    SELECT day, is_bot, views, visitors, country FROM daily_totals;
@@ -0,0 +1,726 @@
+use crate::database::Database;
+use crate::error;
+use crate::models::*;
+use crate::upload::{UploadConfig, UploadResult};
+use crate::visitcounter::{RecorderMetrics, VisitRecorder};
+
+use actix_multipart::Multipart;
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::http::{header, StatusCode};
+use actix_web::{
+    dev::{self, forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    get, post, web, Error, HttpResponse, ResponseError,
+};
+use futures_util::future::LocalBoxFuture;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use subtle::ConstantTimeEq;
+use tera::Tera;
+
+const SESSION_COOKIE: &str = "admin_session";
+const SESSION_LENGTH: i64 = 12 * 60 * 60;
+const PASSWORD_HASH_SCHEME: &str = "pbkdf2-sha256";
+const PASSWORD_HASH_ITERATIONS: u32 = 600_000;
+const STATS_DAYS: i64 = 30;
+const RECENT_VISITS: i64 = 50;
+const DEPLOYMENTS: i64 = 20;
+const LOGIN_ATTEMPTS: usize = 5;
+const LOGIN_ATTEMPT_WINDOW: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+const LOGIN_TRACKED_ADDRESSES: usize = 1024;
+const LOGIN_MAX_VERIFYING: usize = 4;
+
+#[derive(Clone)]
+pub struct AdminConfig {
+    /// Logging in is disabled if no password hash is configured
+    password_hash: Option<String>,
+    session_key: Vec<u8>,
+}
+
+impl AdminConfig {
+    pub fn from_env() -> Self {
+        let password_hash = std::env::var("ADMIN_PASSWORD_HASH").ok();
+        if password_hash.is_none() {
+            log::warn!("No ADMIN_PASSWORD_HASH, the admin dashboard is disabled");
+        }
+
+        // Without a configured key, sessions only last until the next restart
+        let session_key = std::env::var("ADMIN_SESSION_KEY")
+            .map(|k| k.into_bytes())
+            .unwrap_or_else(|_| rand::thread_rng().gen::<[u8; 32]>().to_vec());
+
+        Self {
+            password_hash,
+            session_key,
+        }
+    }
+
+    /// The password hash is part of the signature, so changing the password ends all sessions
+    fn session_mac(&self, expires: i64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.session_key).unwrap();
+        mac.update(expires.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(self.password_hash.as_deref().unwrap_or_default().as_bytes());
+        mac
+    }
+
+    /// A session cookie value of the form `expires.signature`
+    fn new_session(&self) -> String {
+        let expires = chrono::Utc::now().timestamp() + SESSION_LENGTH;
+        let signature = self.session_mac(expires).finalize().into_bytes();
+        format!("{}.{}", expires, hex::encode(signature))
+    }
+
+    fn verify_session(&self, session: &str) -> bool {
+        let Some((expires, signature)) = session.split_once('.') else {
+            return false;
+        };
+        let (Ok(expires), Ok(signature)) = (expires.parse::<i64>(), hex::decode(signature)) else {
+            return false;
+        };
+
+        self.password_hash.is_some()
+            && expires > chrono::Utc::now().timestamp()
+            && self.session_mac(expires).verify_slice(&signature).is_ok()
+    }
+}
+
+/// Limits the login attempts per address, so that the password can not be guessed at the
+/// speed of the server. Successful logins clear the attempts of the address.
+#[derive(Default)]
+pub struct LoginAttempts {
+    attempts: Mutex<HashMap<String, Vec<Instant>>>,
+    verifying: AtomicUsize,
+}
+
+/// Frees a verification slot when the password check is done
+struct Verifying<'a>(&'a AtomicUsize);
+
+impl Drop for Verifying<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl LoginAttempts {
+    /// Records an attempt, or returns false if the address has used up its attempts
+    fn attempt(&self, address: &str) -> bool {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, times| {
+            times.retain(|t| now.duration_since(*t) < LOGIN_ATTEMPT_WINDOW);
+            !times.is_empty()
+        });
+
+        // The address that has been quiet the longest makes room for new ones
+        if attempts.len() >= LOGIN_TRACKED_ADDRESSES && !attempts.contains_key(address) {
+            let oldest = attempts
+                .iter()
+                .min_by_key(|(_, times)| times.last().copied())
+                .map(|(address, _)| address.clone());
+            if let Some(oldest) = oldest {
+                attempts.remove(&oldest);
+            }
+        }
+
+        let times = attempts.entry(address.to_string()).or_default();
+        if times.len() >= LOGIN_ATTEMPTS {
+            return false;
+        }
+        times.push(now);
+        true
+    }
+
+    fn clear(&self, address: &str) {
+        self.attempts.lock().unwrap().remove(address);
+    }
+
+    /// Each check keeps a blocking thread busy, so only a few are run at once
+    fn start_verifying(&self) -> Option<Verifying<'_>> {
+        self.verifying
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < LOGIN_MAX_VERIFYING).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Verifying(&self.verifying))
+    }
+}
+
+fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password, salt, iterations)
+}
+
+/// Hashes the password into the `pbkdf2-sha256$iterations$salt$key` format of `ADMIN_PASSWORD_HASH`
+pub fn hash_password(password: &str) -> String {
+    let salt = rand::thread_rng().gen::<[u8; 16]>();
+    let key = pbkdf2(password.as_bytes(), &salt, PASSWORD_HASH_ITERATIONS);
+
+    format!(
+        "{}${}${}${}",
+        PASSWORD_HASH_SCHEME,
+        PASSWORD_HASH_ITERATIONS,
+        hex::encode(salt),
+        hex::encode(key)
+    )
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    let parts = hash.split('$').collect::<Vec<_>>();
+    let [scheme, iterations, salt, key] = parts[..] else {
+        log::error!("ADMIN_PASSWORD_HASH is not in the expected format");
+        return false;
+    };
+    let (Ok(iterations), Ok(salt), Ok(key)) =
+        (iterations.parse(), hex::decode(salt), hex::decode(key))
+    else {
+        log::error!("ADMIN_PASSWORD_HASH is not in the expected format");
+        return false;
+    };
+
+    scheme == PASSWORD_HASH_SCHEME
+        && pbkdf2(password.as_bytes(), &salt, iterations)
+            .ct_eq(&key[..])
+            .into()
+}
+
+fn render(
+    tmpl: &Mutex<Tera>,
+    template: &str,
+    context: impl serde::Serialize,
+) -> Result<String, Error> {
+    tmpl.lock()
+        .unwrap()
+        .render(template, &tera::Context::from_serialize(context).unwrap())
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Template error"))
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+#[derive(Serialize)]
+struct LoginContext {
+    enabled: bool,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    password: String,
+}
+
+#[get("/login")]
+async fn login_form(
+    tmpl: web::Data<Mutex<Tera>>,
+    config: web::Data<AdminConfig>,
+) -> Result<HttpResponse, Error> {
+    let logincontext = LoginContext {
+        enabled: config.password_hash.is_some(),
+        error: None,
+    };
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(render(
+        &tmpl,
+        "admin/login.html",
+        logincontext,
+    )?))
+}
+
+fn login_error(tmpl: &Mutex<Tera>, status: StatusCode, error: &str) -> Result<HttpResponse, Error> {
+    let logincontext = LoginContext {
+        enabled: true,
+        error: Some(error.to_string()),
+    };
+    Ok(HttpResponse::build(status)
+        .content_type("text/html")
+        .body(render(tmpl, "admin/login.html", logincontext)?))
+}
+
+#[post("/login")]
+async fn login(
+    form: web::Form<LoginForm>,
+    conn: dev::ConnectionInfo,
+    tmpl: web::Data<Mutex<Tera>>,
+    config: web::Data<AdminConfig>,
+    loginattempts: web::Data<LoginAttempts>,
+) -> Result<HttpResponse, Error> {
+    let Some(hash) = config.password_hash.clone() else {
+        return Err(error::Error::NotFound("The admin dashboard is disabled".to_string()).into());
+    };
+
+    let address = conn.realip_remote_addr().unwrap_or_default().to_string();
+    if !loginattempts.attempt(&address) {
+        return login_error(
+            &tmpl,
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many login attempts, try again later",
+        );
+    }
+    let Some(_verifying) = loginattempts.start_verifying() else {
+        return login_error(
+            &tmpl,
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many logins at once, try again later",
+        );
+    };
+
+    // Hashing is deliberately slow, so it is kept off the async workers
+    let password = form.into_inner().password;
+    if !web::block(move || verify_password(&password, &hash)).await? {
+        return login_error(&tmpl, StatusCode::UNAUTHORIZED, "Wrong password");
+    }
+    loginattempts.clear(&address);
+
+    // SameSite=Strict keeps other sites from submitting the admin forms
+    let cookie = Cookie::build(SESSION_COOKIE, config.new_session())
+        .path("/admin")
+        .http_only(true)
+        .secure(conn.scheme() == "https")
+        .same_site(SameSite::Strict)
+        .max_age(Duration::seconds(SESSION_LENGTH))
+        .finish();
+
+    let mut response = redirect("/admin");
+    response.add_cookie(&cookie)?;
+    Ok(response)
+}
+
+#[post("/logout")]
+async fn logout() -> Result<HttpResponse, Error> {
+    let mut response = redirect("/admin/login");
+    response.add_removal_cookie(&Cookie::build(SESSION_COOKIE, "").path("/admin").finish())?;
+    Ok(response)
+}
+
+#[derive(Serialize)]
+struct DashboardContext {
+    message: Option<String>,
+    images: usize,
+    albums: usize,
+    paths: usize,
+    visits: i64,
+    recorder: RecorderMetrics,
+    deployments: Vec<Deployment>,
+}
+
+#[derive(Deserialize)]
+pub struct DashboardQuery {
+    reloaded: Option<String>,
+}
+
+#[get("")]
+async fn dashboard(
+    query: web::Query<DashboardQuery>,
+    tmpl: web::Data<Mutex<Tera>>,
+    db: web::Data<Database>,
+    imagegallery: web::Data<Mutex<ImageGallery>>,
+    recorder: web::Data<VisitRecorder>,
+) -> Result<HttpResponse, Error> {
+    let visits = db.visits_per_path().await?;
+    let (images, albums) = {
+        let imagegallery = imagegallery.lock().unwrap();
+        (imagegallery.images.len(), imagegallery.albums().len())
+    };
+
+    let dashboardcontext = DashboardContext {
+        message: match query.reloaded.as_deref() {
+            Some("blog") => Some("Reloaded the blog".to_string()),
+            Some("templates") => Some("Reloaded the templates".to_string()),
+            _ => None,
+        },
+        images,
+        albums,
+        paths: visits.len(),
+        visits: visits.iter().map(|v| v.visit_count).sum(),
+        recorder: recorder.metrics(),
+        deployments: db.deployments(DEPLOYMENTS).await?,
+    };
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(render(
+        &tmpl,
+        "admin/index.html",
+        dashboardcontext,
+    )?))
+}
+
+#[derive(Deserialize)]
+pub struct ReloadForm {
+    target: String,
+}
+
+#[post("/reload")]
+async fn reload(
+    form: web::Form<ReloadForm>,
+    tmpl: web::Data<Mutex<Tera>>,
+    blogcontext: web::Data<Mutex<BlogContext>>,
+) -> Result<HttpResponse, Error> {
+    match form.target.as_str() {
+        "blog" => blogcontext.lock().unwrap().reload(),
+        "templates" => tmpl.lock().unwrap().full_reload().map_err(|e| {
+            log::error!("Reloading the templates failed: {}", e);
+            actix_web::error::ErrorInternalServerError("Template error")
+        })?,
+        target => {
+            return Err(
+                error::Error::BadRequest(format!("Unknown reload target {}", target)).into(),
+            )
+        }
+    }
+
+    Ok(redirect(&format!("/admin?reloaded={}", form.target)))
+}
+
+#[derive(Serialize)]
+struct AdminGalleryContext {
+    message: Option<String>,
+    results: Vec<UploadResult>,
+    albums: Vec<Album>,
+    images: Vec<Image>,
+}
+
+fn render_gallery(
+    tmpl: &Mutex<Tera>,
+    imagegallery: &Mutex<ImageGallery>,
+    status: StatusCode,
+    message: Option<String>,
+    results: Vec<UploadResult>,
+) -> Result<HttpResponse, Error> {
+    let (albums, images) = {
+        let imagegallery = imagegallery.lock().unwrap();
+        (
+            imagegallery.albums(),
+            imagegallery.ordered(GalleryOrder::Newest, 0),
+        )
+    };
+
+    let gallerycontext = AdminGalleryContext {
+        message,
+        results,
+        albums,
+        images,
+    };
+
+    Ok(HttpResponse::build(status)
+        .content_type("text/html")
+        .body(render(tmpl, "admin/gallery.html", gallerycontext)?))
+}
+
+#[get("/gallery")]
+async fn gallery(
+    tmpl: web::Data<Mutex<Tera>>,
+    imagegallery: web::Data<Mutex<ImageGallery>>,
+) -> Result<HttpResponse, Error> {
+    render_gallery(&tmpl, &imagegallery, StatusCode::OK, None, Vec::new())
+}
+
+#[post("/gallery")]
+async fn upload(
+    payload: Multipart,
+    conn: dev::ConnectionInfo,
+    tmpl: web::Data<Mutex<Tera>>,
+    db: web::Data<Database>,
+    imagegallery: web::Data<Mutex<ImageGallery>>,
+    uploadconfig: web::Data<UploadConfig>,
+) -> Result<HttpResponse, Error> {
+    match crate::upload_images(payload, &conn, &db, &imagegallery, &uploadconfig).await {
+        Ok((status, results)) => render_gallery(&tmpl, &imagegallery, status, None, results),
+        Err(e) => {
+            let status = e.as_response_error().status_code();
+            render_gallery(
+                &tmpl,
+                &imagegallery,
+                status,
+                Some(e.to_string()),
+                Vec::new(),
+            )
+        }
+    }
+}
+
+/// The edit form of a single image. Empty fields remove the value.
+#[derive(Deserialize)]
+pub struct ImageForm {
+    name: String,
+    caption: String,
+    alt: String,
+    album: String,
+    latitude: String,
+    longitude: String,
+    country: String,
+    round: String,
+    game: String,
+}
+
+fn parse_optional<T: FromStr>(field: &str, value: &str) -> Result<Option<T>, error::Error> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| error::Error::BadRequest(format!("Invalid {} {}", field, value)))
+}
+
+impl ImageForm {
+    fn into_patch(self) -> Result<ImagePatch, error::Error> {
+        Ok(ImagePatch {
+            latitude: Some(parse_optional("latitude", &self.latitude)?),
+            longitude: Some(parse_optional("longitude", &self.longitude)?),
+            round: Some(parse_optional("round", &self.round)?),
+            name: Some(self.name),
+            caption: Some(self.caption),
+            alt: Some(self.alt),
+            album: Some(self.album),
+            country: Some(self.country),
+            game: Some(self.game),
+        })
+    }
+}
+
+#[post("/gallery/{name}")]
+async fn edit(
+    path: web::Path<(String,)>,
+    form: web::Form<ImageForm>,
+    tmpl: web::Data<Mutex<Tera>>,
+    db: web::Data<Database>,
+    imagegallery: web::Data<Mutex<ImageGallery>>,
+) -> Result<HttpResponse, Error> {
+    let name = &path.0;
+    let result = match form.into_inner().into_patch() {
+        Ok(patch) => crate::update_gallery_image(name, patch, &db, &imagegallery).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(img) => render_gallery(
+            &tmpl,
+            &imagegallery,
+            StatusCode::OK,
+            Some(format!("Saved {}", img.name)),
+            Vec::new(),
+        ),
+        Err(e) => render_gallery(
+            &tmpl,
+            &imagegallery,
+            e.status_code(),
+            Some(format!("Saving {} failed: {}", name, e)),
+            Vec::new(),
+        ),
+    }
+}
+
+#[post("/gallery/{name}/delete")]
+async fn delete(
+    path: web::Path<(String,)>,
+    tmpl: web::Data<Mutex<Tera>>,
+    db: web::Data<Database>,
+    imagegallery: web::Data<Mutex<ImageGallery>>,
+) -> Result<HttpResponse, Error> {
+    let name = &path.0;
+    let (status, message) = match crate::remove_gallery_image(name, &db, &imagegallery).await {
+        Ok(()) => (StatusCode::OK, format!("Deleted {}", name)),
+        Err(e) => (e.status_code(), format!("Deleting {} failed: {}", name, e)),
+    };
+
+    render_gallery(&tmpl, &imagegallery, status, Some(message), Vec::new())
+}
+
+#[derive(Serialize)]
+struct AdminStatsContext {
+    path: Option<String>,
+    pages: Vec<Visits>,
+    days: Vec<DailyVisits>,
+    max_daily: i64,
+    recent: Vec<RecentVisit>,
+}
+
+#[derive(Deserialize)]
+pub struct AdminStatsQuery {
+    path: Option<String>,
+}
+
+/// Visits per path, or the daily visits and latest visitors of a single path
+#[get("/stats")]
+async fn stats(
+    query: web::Query<AdminStatsQuery>,
+    tmpl: web::Data<Mutex<Tera>>,
+    db: web::Data<Database>,
+) -> Result<HttpResponse, Error> {
+    let mut visits = db.visits_per_path().await?;
+    visits.sort_by_key(|v| -v.visit_count);
+
+    let (days, recent) = match &query.path {
+        Some(path) => (
+            db.visits_per_day(
+                path,
+                chrono::Local::now().naive_local() - chrono::Duration::days(STATS_DAYS),
+            )
+            .await?,
+            db.recent_visits(path, RECENT_VISITS).await?,
+        ),
+        None => (Vec::new(), Vec::new()),
+    };
+
+    let statscontext = AdminStatsContext {
+        path: query.into_inner().path,
+        pages: visits,
+        max_daily: days.iter().map(|d| d.visit_count).max().unwrap_or(1),
+        days,
+        recent,
+    };
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(render(
+        &tmpl,
+        "admin/stats.html",
+        statscontext,
+    )?))
+}
+
+/// Sends requests without a valid session cookie to the login page
+pub struct RequireSession {
+    pub config: AdminConfig,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireSession
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+    S: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequireSessionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireSessionMiddleware {
+            config: self.config.clone(),
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequireSessionMiddleware<S> {
+    config: AdminConfig,
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireSessionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let logged_in = req
+            .cookie(SESSION_COOKIE)
+            .is_some_and(|c| self.config.verify_session(c.value()));
+
+        Box::pin(async move {
+            if !logged_in {
+                return Err(actix_web::error::InternalError::from_response(
+                    "Not logged in",
+                    redirect("/admin/login"),
+                )
+                .into());
+            }
+
+            service.call(req).await
+        })
+    }
+}
+
+/// The routes of the dashboard, to be mounted under `/admin`
+pub fn configure(cfg: &mut web::ServiceConfig, config: AdminConfig) {
+    cfg.service(login_form).service(login).service(
+        web::scope("")
+            .wrap(RequireSession { config })
+            .service(dashboard)
+            .service(logout)
+            .service(reload)
+            .service(gallery)
+            .service(upload)
+            .service(edit)
+            .service(delete)
+            .service(stats),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_password_accepts_the_rfc_7914_vector() {
+        let hash = format!(
+            "pbkdf2-sha256$1${}${}",
+            hex::encode("salt"),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+        assert!(verify_password("passwd", &hash));
+        assert!(!verify_password("password", &hash));
+        assert!(!verify_password(
+            "passwd",
+            &hash.replacen("sha256", "sha512", 1)
+        ));
+        assert!(!verify_password("passwd", "passwd"));
+    }
+
+    #[test]
+    fn login_attempts_are_limited_per_address() {
+        let attempts = LoginAttempts::default();
+        for _ in 0..LOGIN_ATTEMPTS {
+            assert!(attempts.attempt("192.0.2.1"));
+        }
+        assert!(!attempts.attempt("192.0.2.1"));
+        assert!(attempts.attempt("192.0.2.2"));
+
+        attempts.clear("192.0.2.1");
+        assert!(attempts.attempt("192.0.2.1"));
+    }
+
+    #[test]
+    fn login_attempts_track_a_bounded_number_of_addresses() {
+        let attempts = LoginAttempts::default();
+        for i in 0..LOGIN_TRACKED_ADDRESSES + 10 {
+            assert!(attempts.attempt(&i.to_string()));
+        }
+        assert_eq!(
+            attempts.attempts.lock().unwrap().len(),
+            LOGIN_TRACKED_ADDRESSES
+        );
+    }
+
+    #[test]
+    fn concurrent_verifications_are_limited() {
+        let attempts = LoginAttempts::default();
+        let verifying = (0..LOGIN_MAX_VERIFYING)
+            .map(|_| attempts.start_verifying().unwrap())
+            .collect::<Vec<_>>();
+        assert!(attempts.start_verifying().is_none());
+
+        drop(verifying);
+        assert!(attempts.start_verifying().is_some());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::database::Database;
+use crate::error;
+use crate::models::{ApiToken, NewApiToken};
+
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    Error, HttpMessage,
+};
+use futures_util::future::LocalBoxFuture;
+
+const TOKEN_PREFIX: &str = "lajp_";
+
+/// What an API token is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    GalleryWrite,
+    CommentsModerate,
+    StatsRead,
+    /// Grants every other scope as well
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [
+        Self::GalleryWrite,
+        Self::CommentsModerate,
+        Self::StatsRead,
+        Self::Admin,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::GalleryWrite => "gallery:write",
+            Self::CommentsModerate => "comments:moderate",
+            Self::StatsRead => "stats:read",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| {
+                error::Error::BadRequest(format!(
+                    "Unknown scope {}, expected one of {}",
+                    s,
+                    Self::ALL.map(|s| s.as_str()).join(", ")
+                ))
+            })
+    }
+}
+
+impl ApiToken {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes
+            .iter()
+            .any(|s| s == scope.as_str() || s == Scope::Admin.as_str())
+    }
+}
+
+/// Only the hashes of tokens are stored, so a leaked database does not leak usable tokens
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Creates a new random token. Returns the token itself, which is only ever shown once,
+/// along with the row to store.
+pub fn mint(
+    name: &str,
+    scopes: &[Scope],
+    expires: Option<chrono::NaiveDateTime>,
+) -> (String, NewApiToken) {
+    let token = format!(
+        "{}{}",
+        TOKEN_PREFIX,
+        hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+    );
+
+    let row = NewApiToken {
+        name: name.to_string(),
+        hash: hash_token(&token),
+        scopes: scopes.iter().map(|s| s.as_str().to_string()).collect(),
+        created: chrono::Local::now().naive_local(),
+        expires,
+    };
+
+    (token, row)
+}
+
+/// Handles `token mint <name> <scopes> [days]`, `token revoke <name>` and `token list`
+pub async fn command(args: &[String], db: &Database) -> Result<(), error::Error> {
+    let usage = || {
+        error::Error::BadRequest(
+            "Usage: token mint <name> <scope>[,<scope>...] [<days until expiry>] | token revoke <name> | token list"
+                .to_string(),
+        )
+    };
+
+    match args {
+        [cmd, name, scopes, rest @ ..] if cmd == "mint" && rest.len() <= 1 => {
+            let scopes = scopes
+                .split(',')
+                .map(Scope::from_str)
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let expires = match rest.first() {
+                Some(days) => {
+                    let days = days
+                        .parse()
+                        .map_err(|_| error::Error::BadRequest(format!("Invalid days {}", days)))?;
+                    Some(chrono::Local::now().naive_local() + chrono::Duration::days(days))
+                }
+                None => None,
+            };
+
+            let (token, row) = mint(name, &scopes, expires);
+            db.new_api_token(row).await?;
+            println!("{}", token);
+        }
+        [cmd, name] if cmd == "revoke" => {
+            if db.revoke_api_token(name).await? == 0 {
+                return Err(error::Error::NotFound(format!(
+                    "No active token called {}",
+                    name
+                )));
+            }
+            println!("Revoked {}", name);
+        }
+        [cmd] if cmd == "list" => {
+            let now = chrono::Local::now().naive_local();
+            for token in db.api_tokens().await? {
+                let status = if token.revoked.is_some() {
+                    "revoked"
+                } else if token.expires.is_some_and(|e| e <= now) {
+                    "expired"
+                } else {
+                    "active"
+                };
+
+                let format = |t: Option<chrono::NaiveDateTime>| {
+                    t.map(|t| t.format("%F %T").to_string())
+                        .unwrap_or_else(|| "-".to_string())
+                };
+
+                println!(
+                    "{}\t{}\t{}\tcreated {}\texpires {}\tlast used {}",
+                    token.name,
+                    status,
+                    token.scopes.join(","),
+                    token.created.format("%F %T"),
+                    format(token.expires),
+                    format(token.last_used),
+                );
+            }
+        }
+        _ => return Err(usage()),
+    }
+
+    Ok(())
+}
+
+/// Requires a valid `Authorization: Bearer` token with the given scope.
+/// The token is made available to the handlers through the request extensions.
+pub struct RequireScope {
+    pub db: Database,
+    pub scope: Scope,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+    S: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequireScopeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireScopeMiddleware {
+            db: self.db.clone(),
+            scope: self.scope,
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequireScopeMiddleware<S> {
+    db: Database,
+    scope: Scope,
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireScopeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let db = self.db.clone();
+        let scope = self.scope;
+
+        let token_hash = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(|t| hash_token(t.trim()));
+
+        Box::pin(async move {
+            let Some(token_hash) = token_hash else {
+                return Err(error::Error::Unauthorized("No bearer token".to_string()).into());
+            };
+
+            let Some(token) = db.use_api_token(&token_hash).await? else {
+                return Err(error::Error::Unauthorized(
+                    "Invalid, expired or revoked token".to_string(),
+                )
+                .into());
+            };
+
+            if !token.has_scope(scope) {
+                return Err(error::Error::Forbidden(format!(
+                    "The token does not have the {} scope",
+                    scope
+                ))
+                .into());
+            }
+
+            req.extensions_mut().insert(token);
+            service.call(req).await
+        })
+    }
+}
@@ -0,0 +1,167 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Lowercase substrings of the user agents of crawlers, link previewers, uptime checkers and
+/// HTTP libraries. Keep in sync with the pattern in the `visits_is_bot` migration.
+const SIGNATURES: [&str; 29] = [
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "archiver",
+    "facebookexternalhit",
+    "embedly",
+    "bingpreview",
+    "uptime",
+    "pingdom",
+    "statuscake",
+    "monitor",
+    "headlesschrome",
+    "lighthouse",
+    "curl/",
+    "wget/",
+    "python-",
+    "go-http-client",
+    "java/",
+    "okhttp",
+    "libwww-perl",
+    "httpclient",
+    "axios/",
+    "node-fetch",
+    "scrapy",
+    "feedfetcher",
+    "zgrab",
+    "masscan",
+    "nmap",
+];
+
+/// Whether the user agent belongs to something other than a person's browser
+pub fn is_bot_agent(user_agent: &str) -> bool {
+    let lowercase = user_agent.to_lowercase();
+    SIGNATURES.iter().any(|s| lowercase.contains(s))
+}
+
+/// Tells visits by bots apart from those by people
+pub struct BotDetector {
+    /// Address ranges of known crawlers
+    ranges: Vec<IpNet>,
+}
+
+impl BotDetector {
+    /// Reads the crawler address ranges from the file in `BOT_IP_RANGES_FILE`, one address or
+    /// CIDR range per line. Empty lines and lines starting with `#` are skipped.
+    pub fn from_env() -> Self {
+        let ranges = match std::env::var("BOT_IP_RANGES_FILE") {
+            Ok(file) => std::fs::read_to_string(&file)
+                .unwrap_or_else(|e| panic!("Failed to read BOT_IP_RANGES_FILE {}: {}", file, e))
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(|l| {
+                    l.parse::<IpNet>()
+                        .or_else(|_| l.parse::<IpAddr>().map(IpNet::from))
+                        .unwrap_or_else(|_| panic!("Invalid address range {} in {}", l, file))
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        Self { ranges }
+    }
+
+    /// Browsers always send a user agent and the languages they prefer,
+    /// so a request without either is taken to be from a bot
+    pub fn is_bot(
+        &self,
+        user_agent: Option<&str>,
+        accept_language: Option<&str>,
+        address: Option<IpAddr>,
+    ) -> bool {
+        let Some(user_agent) = user_agent.filter(|ua| !ua.is_empty()) else {
+            return true;
+        };
+        if accept_language.filter(|l| !l.is_empty()).is_none() || is_bot_agent(user_agent) {
+            return true;
+        }
+
+        address.is_some_and(|a| self.ranges.iter().any(|r| r.contains(&a)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/117.0";
+
+    fn detector(ranges: &[&str]) -> BotDetector {
+        BotDetector {
+            ranges: ranges.iter().map(|r| r.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn empty_user_agent_is_a_bot() {
+        let bots = detector(&[]);
+        assert!(bots.is_bot(None, Some("en"), None));
+        assert!(bots.is_bot(Some(""), Some("en"), None));
+    }
+
+    #[test]
+    fn missing_accept_language_is_a_bot() {
+        let bots = detector(&[]);
+        assert!(bots.is_bot(Some(FIREFOX), None, None));
+        assert!(bots.is_bot(Some(FIREFOX), Some(""), None));
+    }
+
+    #[test]
+    fn crawlers_are_bots() {
+        let bots = detector(&[]);
+        for user_agent in [
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "facebookexternalhit/1.1",
+            "curl/8.1.2",
+            "python-requests/2.31.0",
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 HeadlessChrome/116.0.0.0",
+        ] {
+            assert!(is_bot_agent(user_agent), "{}", user_agent);
+            assert!(bots.is_bot(Some(user_agent), Some("en"), None));
+        }
+    }
+
+    #[test]
+    fn browsers_are_not_bots() {
+        let bots = detector(&[]);
+        for user_agent in [
+            FIREFOX,
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/116.0.0.0 Safari/537.36",
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.6 Mobile/15E148 Safari/604.1",
+        ] {
+            assert!(!is_bot_agent(user_agent), "{}", user_agent);
+            assert!(!bots.is_bot(Some(user_agent), Some("fi-FI,fi;q=0.9"), None));
+        }
+    }
+
+    #[test]
+    fn migration_has_every_signature() {
+        let migration = include_str!("../migrations/2023-08-19-110000_visits_is_bot/up.sql");
+        let pattern = migration
+            .split_once("~* '(")
+            .and_then(|(_, rest)| rest.split_once(")'"))
+            .map(|(pattern, _)| pattern.split('|').collect::<Vec<_>>())
+            .unwrap();
+        assert_eq!(pattern, SIGNATURES);
+    }
+
+    #[test]
+    fn addresses_in_the_ranges_are_bots() {
+        let bots = detector(&["66.249.64.0/19", "2001:4860:4801::/48", "192.0.2.1/32"]);
+        for address in ["66.249.66.1", "2001:4860:4801:10::1", "192.0.2.1"] {
+            assert!(bots.is_bot(Some(FIREFOX), Some("en"), address.parse().ok()));
+        }
+        for address in ["66.249.96.1", "2001:4860:4802::1", "192.0.2.2"] {
+            assert!(!bots.is_bot(Some(FIREFOX), Some("en"), address.parse().ok()));
+        }
+        assert!(!bots.is_bot(Some(FIREFOX), Some("en"), None));
+    }
+}
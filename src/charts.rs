@@ -0,0 +1,278 @@
+use crate::models::{PeriodPathVisits, PeriodVisits, StatsInterval};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const CHART_WIDTH: f64 = 640.0;
+const CHART_HEIGHT: f64 = 200.0;
+/// Room left around the plot for the labels
+const CHART_LEFT: f64 = 40.0;
+const CHART_RIGHT: f64 = 10.0;
+const CHART_TOP: f64 = 10.0;
+const CHART_BOTTOM: f64 = 20.0;
+
+const SPARKLINE_WIDTH: f64 = 100.0;
+const SPARKLINE_HEIGHT: f64 = 20.0;
+
+/// How a period is labelled, matching the table on the stats page
+fn period_label(period: chrono::NaiveDate, interval: StatsInterval) -> String {
+    match interval {
+        StatsInterval::Day => period.format("%F"),
+        StatsInterval::Week => period.format("%G-W%V"),
+        StatsInterval::Month => period.format("%Y-%m"),
+    }
+    .to_string()
+}
+
+/// The x coordinates of `count` evenly spaced points between `left` and `right`
+fn xs(count: usize, left: f64, right: f64) -> impl Iterator<Item = f64> {
+    let step = if count > 1 {
+        (right - left) / (count - 1) as f64
+    } else {
+        0.0
+    };
+    let start = if count > 1 {
+        left
+    } else {
+        (left + right) / 2.0
+    };
+    (0..count).map(move |i| start + step * i as f64)
+}
+
+fn points(values: &[i64], max: i64, left: f64, right: f64, top: f64, bottom: f64) -> String {
+    xs(values.len(), left, right)
+        .zip(values)
+        .map(|(x, v)| {
+            let y = bottom - (bottom - top) * *v as f64 / max.max(1) as f64;
+            format!("{:.1},{:.1}", x, y)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// An area chart of the views in each period with a line for the visitors.
+/// Hovering over a period shows its numbers.
+pub fn traffic_chart(periods: &[PeriodVisits], interval: StatsInterval) -> String {
+    let (left, right) = (CHART_LEFT, CHART_WIDTH - CHART_RIGHT);
+    let (top, bottom) = (CHART_TOP, CHART_HEIGHT - CHART_BOTTOM);
+
+    let views = periods.iter().map(|p| p.views).collect::<Vec<_>>();
+    let visitors = periods.iter().map(|p| p.visitors).collect::<Vec<_>>();
+    let max = views.iter().copied().max().unwrap_or_default();
+
+    let mut svg = format!(
+        r#"<svg class="chart" viewBox="0 0 {} {}" role="img" aria-label="Views and visitors by {}">"#,
+        CHART_WIDTH,
+        CHART_HEIGHT,
+        interval.as_str()
+    );
+
+    // The axes along with the largest value and the first and last periods
+    let _ = write!(
+        svg,
+        r#"<path class="axis" d="M{left},{top} V{bottom} H{right}"/><text x="{x}" y="{y}" text-anchor="end">{max}</text><text x="{x}" y="{bottom}" text-anchor="end">0</text>"#,
+        x = left - 4.0,
+        y = top + 10.0,
+    );
+    let y = CHART_HEIGHT - 4.0;
+    if let Some(first) = periods.first() {
+        let _ = write!(
+            svg,
+            r#"<text x="{left}" y="{y}">{}</text>"#,
+            period_label(first.period, interval),
+        );
+    }
+    if let Some(last) = periods.last().filter(|_| periods.len() > 1) {
+        let _ = write!(
+            svg,
+            r#"<text x="{right}" y="{y}" text-anchor="end">{}</text>"#,
+            period_label(last.period, interval),
+        );
+    }
+
+    let views_points = points(&views, max, left, right, top, bottom);
+    let _ = write!(
+        svg,
+        r#"<polygon class="views" points="{left},{bottom} {views_points} {right},{bottom}"/><polyline class="visitors" points="{}"/>"#,
+        points(&visitors, max, left, right, top, bottom),
+    );
+
+    for (x, period) in xs(periods.len(), left, right).zip(periods) {
+        let y = bottom - (bottom - top) * period.views as f64 / max.max(1) as f64;
+        let _ = write!(
+            svg,
+            r#"<circle cx="{:.1}" cy="{:.1}" r="3"><title>{}: {} views, {} visitors</title></circle>"#,
+            x,
+            y,
+            period_label(period.period, interval),
+            period.views,
+            period.visitors
+        );
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+/// A small line of the views in each period without any labels
+pub fn sparkline(values: &[i64]) -> String {
+    let max = values.iter().copied().max().unwrap_or_default();
+    format!(
+        r#"<svg class="sparkline" width="{w}" height="{h}" viewBox="0 0 {w} {h}" aria-hidden="true"><polyline points="{}"/></svg>"#,
+        points(
+            values,
+            max,
+            1.0,
+            SPARKLINE_WIDTH - 1.0,
+            1.0,
+            SPARKLINE_HEIGHT - 1.0
+        ),
+        w = SPARKLINE_WIDTH,
+        h = SPARKLINE_HEIGHT,
+    )
+}
+
+/// A sparkline for each path, with the periods without visits filled in from `periods`
+pub fn sparklines(
+    periods: &[PeriodVisits],
+    path_visits: &[PeriodPathVisits],
+) -> HashMap<String, String> {
+    let index = periods
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.period, i))
+        .collect::<HashMap<_, _>>();
+
+    let mut series = HashMap::<&str, Vec<i64>>::new();
+    for visits in path_visits {
+        if let Some(i) = index.get(&visits.period) {
+            series
+                .entry(&visits.path)
+                .or_insert_with(|| vec![0; periods.len()])[*i] += visits.views;
+        }
+    }
+
+    series
+        .into_iter()
+        .map(|(path, values)| (path.to_string(), sparkline(&values)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 8, d).unwrap()
+    }
+
+    fn period(d: u32, views: i64, visitors: i64) -> PeriodVisits {
+        PeriodVisits {
+            period: day(d),
+            views,
+            visitors,
+        }
+    }
+
+    fn path_visits(d: u32, path: &str, views: i64) -> PeriodPathVisits {
+        PeriodPathVisits {
+            period: day(d),
+            path: path.to_string(),
+            views,
+            visitors: views,
+        }
+    }
+
+    #[test]
+    fn xs_are_evenly_spaced() {
+        assert_eq!(xs(3, 0.0, 10.0).collect::<Vec<_>>(), [0.0, 5.0, 10.0]);
+        assert_eq!(xs(0, 0.0, 10.0).count(), 0);
+    }
+
+    #[test]
+    fn single_x_is_centered() {
+        assert_eq!(xs(1, 0.0, 10.0).collect::<Vec<_>>(), [5.0]);
+    }
+
+    #[test]
+    fn points_are_scaled_to_the_max() {
+        assert_eq!(
+            points(&[0, 5, 10], 10, 0.0, 10.0, 0.0, 20.0),
+            "0.0,20.0 5.0,10.0 10.0,0.0"
+        );
+    }
+
+    #[test]
+    fn zero_points_lie_on_the_bottom() {
+        assert_eq!(
+            points(&[0, 0], 0, 0.0, 10.0, 0.0, 20.0),
+            "0.0,20.0 10.0,20.0"
+        );
+        assert_eq!(points(&[], 0, 0.0, 10.0, 0.0, 20.0), "");
+    }
+
+    #[test]
+    fn traffic_chart_of_a_single_period() {
+        let svg = traffic_chart(&[period(1, 3, 2)], StatsInterval::Day);
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>"));
+        assert_eq!(svg.matches("<circle").count(), 1);
+        assert_eq!(svg.matches("2023-08-01").count(), 2);
+        assert!(svg.contains("2023-08-01: 3 views, 2 visitors"));
+        assert!(!svg.contains("NaN"));
+    }
+
+    #[test]
+    fn traffic_chart_without_views() {
+        let periods = [period(1, 0, 0), period(2, 0, 0), period(3, 0, 0)];
+        let svg = traffic_chart(&periods, StatsInterval::Week);
+        assert_eq!(svg.matches("<circle").count(), 3);
+        assert!(svg.contains("2023-W31"));
+        assert!(!svg.contains("NaN") && !svg.contains("inf"));
+    }
+
+    #[test]
+    fn traffic_chart_without_periods() {
+        let svg = traffic_chart(&[], StatsInterval::Month);
+        assert!(!svg.contains("<circle") && !svg.contains("NaN"));
+    }
+
+    #[test]
+    fn sparklines_fill_in_periods_without_visits() {
+        let periods = [period(1, 4, 1), period(2, 0, 0), period(3, 5, 1)];
+        let sparklines = sparklines(
+            &periods,
+            &[
+                path_visits(1, "/", 4),
+                path_visits(3, "/", 2),
+                path_visits(3, "/blog", 3),
+            ],
+        );
+
+        assert_eq!(sparklines.len(), 2);
+        assert_eq!(sparklines["/"], sparkline(&[4, 0, 2]));
+        assert_eq!(sparklines["/blog"], sparkline(&[0, 0, 3]));
+    }
+
+    #[test]
+    fn sparklines_skip_periods_missing_from_the_index() {
+        let periods = [period(1, 1, 1)];
+        let sparklines = sparklines(
+            &periods,
+            &[
+                path_visits(1, "/", 1),
+                path_visits(9, "/", 7),
+                path_visits(9, "/old", 7),
+            ],
+        );
+
+        assert_eq!(sparklines.len(), 1);
+        assert_eq!(sparklines["/"], sparkline(&[1]));
+    }
+
+    #[test]
+    fn sparkline_without_views_is_flat() {
+        let svg = sparkline(&[0, 0, 0]);
+        assert!(svg.contains(r#"points="1.0,19.0 50.0,19.0 99.0,19.0""#));
+    }
+}
@@ -1,4 +1,8 @@
-use crate::models::Visits;
+use crate::models::{
+    ApiToken, CountryVisits, DailyVisits, Deployment, ImageChanges, ImageMetadata, ImageRow,
+    NewApiToken, NewDeployment, NewImage, PathVisits, PeriodPathVisits, PeriodVisits, RecentVisit,
+    ReferrerVisits, StatsInterval, UserAgentVisits, VisitTotals, Visits,
+};
 use crate::visitcounter::Visit;
 
 use crate::diesel::prelude::*;
@@ -10,6 +14,17 @@ use diesel::{
     r2d2::{ConnectionManager, Pool},
 };
 
+/// The views and visitors of each day of the whole site, or of the page in the parameter `$n`
+/// if it is not null. Both the kept and the rolled up visits are included.
+fn days(n: usize) -> String {
+    format!(
+        "(SELECT day, is_bot, country, views, visitors FROM site_days WHERE ${0}::text IS NULL \
+         UNION ALL SELECT day, is_bot, country, views, visitors FROM page_days WHERE path = ${0}) \
+         AS days",
+        n
+    )
+}
+
 #[derive(Clone)]
 pub struct Database {
     pool: Pool<ConnectionManager<PgConnection>>,
@@ -18,7 +33,10 @@ pub struct Database {
 impl Database {
     pub fn new() -> Self {
         let db_url = env::var("DATABASE_URL").expect("DATABASE_URL not present in env");
+        Self::from_url(db_url)
+    }
 
+    pub fn from_url(db_url: String) -> Self {
         let manager = ConnectionManager::<PgConnection>::new(db_url);
         let pool = Pool::builder()
             .build(manager)
@@ -27,28 +45,530 @@ impl Database {
     }
 
     // TODO: Do proper error handling
-    pub async fn new_visit(&self, visit: Visit) -> Result<usize, crate::error::Error> {
+    /// Blocks until the visits are stored, so it is run on the blocking threads
+    pub fn new_visits(&self, batch: &[Visit]) -> Result<usize, crate::error::Error> {
         use crate::schema::visits::dsl::*;
         let mut conn = self.pool.get()?;
 
         Ok(diesel::insert_into(visits)
-            .values(&visit)
+            .values(batch)
             .execute(&mut conn)?)
     }
 
+    /// The salt for hashing the addresses of today's visitors, `new_salt` if there is none yet.
+    /// The salts of earlier days are deleted so that their hashes cannot be traced back.
+    pub async fn visitor_salt(
+        &self,
+        today: chrono::NaiveDate,
+        new_salt: String,
+    ) -> Result<String, crate::error::Error> {
+        use crate::schema::visitor_salts::dsl::*;
+        let mut conn = self.pool.get()?;
+
+        Ok(conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::delete(visitor_salts.filter(day.lt(today))).execute(conn)?;
+
+            diesel::insert_into(visitor_salts)
+                .values((day.eq(today), salt.eq(&new_salt)))
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+
+            visitor_salts.find(today).select(salt).first::<String>(conn)
+        })?)
+    }
+
+    /// Adds up the successful visits before `before` into the daily tables and deletes all of
+    /// them, returning the number of deleted visits. Blocks until done, so it is run on the
+    /// blocking threads.
+    ///
+    /// Views add up, but unique visitors do not: if a day were rolled up in two passes, someone
+    /// visiting in both would be counted twice. `before` is therefore a midnight long enough ago
+    /// that no more visits arrive for the days before it, so that each day is rolled up once.
+    pub fn roll_up_visits(
+        &self,
+        before: chrono::NaiveDateTime,
+    ) -> Result<usize, crate::error::Error> {
+        use diesel::sql_types::Timestamp;
+        let mut conn = self.pool.get()?;
+
+        Ok(conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::sql_query(
+                "INSERT INTO daily_visits \
+                 SELECT instance::date AS day, path, is_bot, COUNT(*), COUNT(DISTINCT visitor), \
+                 COALESCE(SUM(latency_ms), 0), COUNT(latency_ms), COALESCE(country, '') AS code \
+                 FROM visits WHERE instance < $1 AND (status IS NULL OR status BETWEEN 200 AND 299) \
+                 GROUP BY day, path, is_bot, code \
+                 ON CONFLICT (day, path, is_bot, country) DO UPDATE SET \
+                 views = daily_visits.views + excluded.views, \
+                 visitors = daily_visits.visitors + excluded.visitors, \
+                 latency_ms_sum = daily_visits.latency_ms_sum + excluded.latency_ms_sum, \
+                 latency_count = daily_visits.latency_count + excluded.latency_count",
+            )
+            .bind::<Timestamp, _>(before)
+            .execute(conn)?;
+
+            diesel::sql_query(
+                "INSERT INTO daily_totals \
+                 SELECT instance::date AS day, is_bot, COUNT(*), COUNT(DISTINCT visitor), \
+                 COALESCE(country, '') AS code FROM visits \
+                 WHERE instance < $1 AND (status IS NULL OR status BETWEEN 200 AND 299) \
+                 GROUP BY day, is_bot, code \
+                 ON CONFLICT (day, is_bot, country) DO UPDATE SET \
+                 views = daily_totals.views + excluded.views, \
+                 visitors = daily_totals.visitors + excluded.visitors",
+            )
+            .bind::<Timestamp, _>(before)
+            .execute(conn)?;
+
+            use crate::schema::visits::dsl::*;
+            diesel::delete(visits.filter(instance.lt(before))).execute(conn)
+        })?)
+    }
+
     pub async fn visits_per_path(&self) -> Result<Vec<Visits>, crate::error::Error> {
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::sql_query(
+            "SELECT path, SUM(views)::bigint AS visit_count FROM page_days GROUP BY path",
+        )
+        .load::<Visits>(&mut conn)?)
+    }
+
+    /// Views and unique visitors for every period between `from` and `to`, including the empty
+    /// ones. Only visits to `visit_path` are counted if it is given, and visits by bots only if
+    /// `bots` is set.
+    pub async fn visits_over_time(
+        &self,
+        interval: StatsInterval,
+        from: chrono::NaiveDateTime,
+        to: chrono::NaiveDateTime,
+        visit_path: Option<&str>,
+        bots: bool,
+    ) -> Result<Vec<PeriodVisits>, crate::error::Error> {
+        use diesel::sql_types::{Bool, Nullable, Text, Timestamp};
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::sql_query(format!(
+            "SELECT periods.period::date AS period, COALESCE(SUM(days.views), 0)::bigint AS views, \
+             COALESCE(SUM(days.visitors), 0)::bigint AS visitors \
+             FROM generate_series(date_trunc($1, $2::timestamp), $3::timestamp - interval '1 second', \
+             ('1 ' || $1)::interval) AS periods (period) \
+             LEFT JOIN {} ON date_trunc($1, days.day::timestamp) = periods.period \
+             AND days.day >= $2 AND days.day < $3 AND ($5 OR NOT days.is_bot) \
+             GROUP BY periods.period ORDER BY periods.period",
+            days(4)
+        ))
+        .bind::<Text, _>(interval.as_str())
+        .bind::<Timestamp, _>(from)
+        .bind::<Timestamp, _>(to)
+        .bind::<Nullable<Text>, _>(visit_path)
+        .bind::<Bool, _>(bots)
+        .load::<PeriodVisits>(&mut conn)?)
+    }
+
+    /// Views and unique visitors of each path in each period between `from` and `to`.
+    /// Unlike in `visits_over_time`, periods without visits are left out.
+    pub async fn path_visits_over_time(
+        &self,
+        interval: StatsInterval,
+        from: chrono::NaiveDateTime,
+        to: chrono::NaiveDateTime,
+        visit_path: Option<&str>,
+        bots: bool,
+    ) -> Result<Vec<PeriodPathVisits>, crate::error::Error> {
+        use diesel::sql_types::{Bool, Nullable, Text, Timestamp};
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::sql_query(
+            "SELECT date_trunc($1, day::timestamp)::date AS period, path, \
+             SUM(views)::bigint AS views, SUM(visitors)::bigint AS visitors FROM page_days \
+             WHERE day >= $2 AND day < $3 AND ($4::text IS NULL OR path = $4) \
+             AND ($5 OR NOT is_bot) GROUP BY period, path ORDER BY period, views DESC, path",
+        )
+        .bind::<Text, _>(interval.as_str())
+        .bind::<Timestamp, _>(from)
+        .bind::<Timestamp, _>(to)
+        .bind::<Nullable<Text>, _>(visit_path)
+        .bind::<Bool, _>(bots)
+        .load::<PeriodPathVisits>(&mut conn)?)
+    }
+
+    /// Views and unique visitors of each path between `from` and `to`, most viewed first
+    pub async fn path_visits(
+        &self,
+        from: chrono::NaiveDateTime,
+        to: chrono::NaiveDateTime,
+        bots: bool,
+    ) -> Result<Vec<PathVisits>, crate::error::Error> {
+        use diesel::sql_types::{Bool, Timestamp};
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::sql_query(
+            "SELECT path, SUM(views)::bigint AS views, SUM(visitors)::bigint AS visitors, \
+             (SUM(latency_ms_sum) / NULLIF(SUM(latency_count), 0))::integer AS latency \
+             FROM page_days WHERE day >= $1 AND day < $2 AND ($3 OR NOT is_bot) \
+             GROUP BY path ORDER BY views DESC, path",
+        )
+        .bind::<Timestamp, _>(from)
+        .bind::<Timestamp, _>(to)
+        .bind::<Bool, _>(bots)
+        .load::<PathVisits>(&mut conn)?)
+    }
+
+    /// Views and unique visitors from each country between `from` and `to`, most viewed first
+    pub async fn country_visits(
+        &self,
+        from: chrono::NaiveDateTime,
+        to: chrono::NaiveDateTime,
+        visit_path: Option<&str>,
+        bots: bool,
+    ) -> Result<Vec<CountryVisits>, crate::error::Error> {
+        use diesel::sql_types::{Bool, Nullable, Text, Timestamp};
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::sql_query(format!(
+            "SELECT NULLIF(country, '') AS country, SUM(views)::bigint AS views, \
+             SUM(visitors)::bigint AS visitors FROM {} \
+             WHERE day >= $1 AND day < $2 AND ($4 OR NOT is_bot) \
+             GROUP BY country ORDER BY views DESC, country",
+            days(3)
+        ))
+        .bind::<Timestamp, _>(from)
+        .bind::<Timestamp, _>(to)
+        .bind::<Nullable<Text>, _>(visit_path)
+        .bind::<Bool, _>(bots)
+        .load::<CountryVisits>(&mut conn)?)
+    }
+
+    /// The sites linking to the most viewed pages between `from` and `to`.
+    /// Only the visits that have not been rolled up are counted.
+    pub async fn top_referrers(
+        &self,
+        from: chrono::NaiveDateTime,
+        to: chrono::NaiveDateTime,
+        visit_path: Option<&str>,
+        bots: bool,
+        limit: i64,
+    ) -> Result<Vec<ReferrerVisits>, crate::error::Error> {
+        use diesel::sql_types::{BigInt, Bool, Nullable, Text, Timestamp};
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::sql_query(
+            "SELECT referrer, COUNT(*) AS views, COUNT(DISTINCT visitor) AS visitors FROM visits \
+             WHERE instance >= $1 AND instance < $2 AND ($3::text IS NULL OR path = $3) \
+             AND ($4 OR NOT is_bot) AND referrer IS NOT NULL \
+             AND (status IS NULL OR status BETWEEN 200 AND 299) \
+             GROUP BY referrer ORDER BY views DESC, referrer LIMIT $5",
+        )
+        .bind::<Timestamp, _>(from)
+        .bind::<Timestamp, _>(to)
+        .bind::<Nullable<Text>, _>(visit_path)
+        .bind::<Bool, _>(bots)
+        .bind::<BigInt, _>(limit)
+        .load::<ReferrerVisits>(&mut conn)?)
+    }
+
+    /// The views by each distinct user agent between `from` and `to`.
+    /// Only the visits that have not been rolled up are counted.
+    pub async fn user_agent_visits(
+        &self,
+        from: chrono::NaiveDateTime,
+        to: chrono::NaiveDateTime,
+        visit_path: Option<&str>,
+        bots: bool,
+    ) -> Result<Vec<UserAgentVisits>, crate::error::Error> {
+        use diesel::sql_types::{Bool, Nullable, Text, Timestamp};
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::sql_query(
+            "SELECT user_agent, COUNT(*) AS views FROM visits \
+             WHERE instance >= $1 AND instance < $2 AND ($3::text IS NULL OR path = $3) \
+             AND ($4 OR NOT is_bot) AND (status IS NULL OR status BETWEEN 200 AND 299) \
+             GROUP BY user_agent",
+        )
+        .bind::<Timestamp, _>(from)
+        .bind::<Timestamp, _>(to)
+        .bind::<Nullable<Text>, _>(visit_path)
+        .bind::<Bool, _>(bots)
+        .load::<UserAgentVisits>(&mut conn)?)
+    }
+
+    /// Visitors are counted separately for each day, since that is as long as they are recognised
+    pub async fn visit_totals(
+        &self,
+        from: chrono::NaiveDateTime,
+        to: chrono::NaiveDateTime,
+        visit_path: Option<&str>,
+        bots: bool,
+    ) -> Result<VisitTotals, crate::error::Error> {
+        use diesel::sql_types::{Bool, Nullable, Text, Timestamp};
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::sql_query(format!(
+            "SELECT COALESCE(SUM(views), 0)::bigint AS views, \
+             COALESCE(SUM(visitors), 0)::bigint AS visitors FROM {} \
+             WHERE day >= $1 AND day < $2 AND ($4 OR NOT is_bot)",
+            days(3)
+        ))
+        .bind::<Timestamp, _>(from)
+        .bind::<Timestamp, _>(to)
+        .bind::<Nullable<Text>, _>(visit_path)
+        .bind::<Bool, _>(bots)
+        .get_result::<VisitTotals>(&mut conn)?)
+    }
+
+    /// The number of visits to the path on each day since `since`, days without visits are left out
+    pub async fn visits_per_day(
+        &self,
+        visit_path: &str,
+        since: chrono::NaiveDateTime,
+    ) -> Result<Vec<DailyVisits>, crate::error::Error> {
+        use diesel::sql_types::{Text, Timestamp};
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::sql_query(
+            "SELECT day, SUM(views)::bigint AS visit_count FROM page_days \
+             WHERE path = $1 AND day >= $2 GROUP BY day ORDER BY day",
+        )
+        .bind::<Text, _>(visit_path)
+        .bind::<Timestamp, _>(since)
+        .load::<DailyVisits>(&mut conn)?)
+    }
+
+    pub async fn recent_visits(
+        &self,
+        visit_path: &str,
+        limit: i64,
+    ) -> Result<Vec<RecentVisit>, crate::error::Error> {
         use crate::schema::visits::dsl::*;
         let mut conn = self.pool.get()?;
 
         Ok(visits
-            .group_by(path)
-            .select((path, diesel::dsl::count(path)))
-            .load::<(String, i64)>(&mut conn)?
-            .iter()
-            .map(|(a, b)| Visits {
-                path: a.to_string(),
-                visit_count: *b,
-            })
-            .collect())
+            .filter(path.eq(visit_path))
+            .filter(status.is_null().or(status.between(200, 299)))
+            .order(instance.desc())
+            .limit(limit)
+            .select((visitor, instance))
+            .load::<RecentVisit>(&mut conn)?)
+    }
+
+    pub async fn new_image_metadata(
+        &self,
+        entry: ImageMetadata,
+    ) -> Result<usize, crate::error::Error> {
+        use crate::schema::image_metadata::dsl::*;
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::insert_into(image_metadata)
+            .values(&entry)
+            .on_conflict_do_nothing()
+            .execute(&mut conn)?)
+    }
+
+    pub async fn images(&self) -> Result<Vec<ImageRow>, crate::error::Error> {
+        use crate::schema::images::dsl::*;
+        let mut conn = self.pool.get()?;
+
+        Ok(images.order(uploaded).load::<ImageRow>(&mut conn)?)
+    }
+
+    /// Inserts the image, or returns the existing row if one with the same path exists
+    pub async fn new_image(&self, image: NewImage) -> Result<ImageRow, crate::error::Error> {
+        use crate::schema::images::dsl::*;
+        let mut conn = self.pool.get()?;
+
+        diesel::insert_into(images)
+            .values(&image)
+            .on_conflict(path)
+            .do_nothing()
+            .execute(&mut conn)?;
+
+        Ok(images
+            .filter(path.eq(&image.path))
+            .first::<ImageRow>(&mut conn)?)
+    }
+
+    pub async fn update_image(
+        &self,
+        image_id: i32,
+        changes: ImageChanges,
+    ) -> Result<ImageRow, crate::error::Error> {
+        use crate::schema::images::dsl::*;
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::update(images.find(image_id))
+            .set(&changes)
+            .get_result::<ImageRow>(&mut conn)?)
+    }
+
+    pub async fn delete_image(&self, image_id: i32) -> Result<usize, crate::error::Error> {
+        use crate::schema::images::dsl::*;
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::delete(images.find(image_id)).execute(&mut conn)?)
+    }
+
+    pub async fn new_api_token(&self, token: NewApiToken) -> Result<ApiToken, crate::error::Error> {
+        use crate::schema::api_tokens::dsl::*;
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::insert_into(api_tokens)
+            .values(&token)
+            .returning(ApiToken::as_returning())
+            .get_result(&mut conn)?)
+    }
+
+    pub async fn api_tokens(&self) -> Result<Vec<ApiToken>, crate::error::Error> {
+        use crate::schema::api_tokens::dsl::*;
+        let mut conn = self.pool.get()?;
+
+        Ok(api_tokens
+            .order(created)
+            .select(ApiToken::as_select())
+            .load(&mut conn)?)
+    }
+
+    /// Finds the token with the given hash, marking it as used if it is still valid
+    pub async fn use_api_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<ApiToken>, crate::error::Error> {
+        use crate::schema::api_tokens::dsl::*;
+        let mut conn = self.pool.get()?;
+        let now = chrono::Local::now().naive_local();
+
+        Ok(diesel::update(
+            api_tokens
+                .filter(hash.eq(token_hash))
+                .filter(revoked.is_null())
+                .filter(expires.is_null().or(expires.gt(now))),
+        )
+        .set(last_used.eq(now))
+        .returning(ApiToken::as_returning())
+        .get_result(&mut conn)
+        .optional()?)
+    }
+
+    /// Returns the number of revoked tokens, which is 0 if there is no active token with the name
+    pub async fn revoke_api_token(&self, token_name: &str) -> Result<usize, crate::error::Error> {
+        use crate::schema::api_tokens::dsl::*;
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::update(
+            api_tokens
+                .filter(name.eq(token_name))
+                .filter(revoked.is_null()),
+        )
+        .set(revoked.eq(chrono::Local::now().naive_local()))
+        .execute(&mut conn)?)
+    }
+
+    pub async fn new_deployment(
+        &self,
+        deployment: NewDeployment,
+    ) -> Result<usize, crate::error::Error> {
+        use crate::schema::deployments::dsl::*;
+        let mut conn = self.pool.get()?;
+
+        Ok(diesel::insert_into(deployments)
+            .values(&deployment)
+            .execute(&mut conn)?)
+    }
+
+    /// The most recent deployments, newest first
+    pub async fn deployments(&self, limit: i64) -> Result<Vec<Deployment>, crate::error::Error> {
+        use crate::schema::deployments::dsl::*;
+        let mut conn = self.pool.get()?;
+
+        Ok(deployments
+            .order(started.desc())
+            .limit(limit)
+            .load::<Deployment>(&mut conn)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Everything the stats page shows about the visits of early 1990
+    async fn stats(db: &Database) -> serde_json::Value {
+        let day = |d| {
+            chrono::NaiveDate::from_ymd_opt(1990, 1, d)
+                .unwrap()
+                .and_time(chrono::NaiveTime::MIN)
+        };
+        let (from, to) = (day(1), day(5));
+
+        let mut stats = Vec::new();
+        for bots in [false, true] {
+            for path in [None, Some("/"), Some("/blog")] {
+                stats.push(serde_json::json!({
+                    "totals": db.visit_totals(from, to, path, bots).await.unwrap(),
+                    "days": db
+                        .visits_over_time(StatsInterval::Day, from, to, path, bots)
+                        .await
+                        .unwrap(),
+                    "paths": db
+                        .path_visits_over_time(StatsInterval::Day, from, to, path, bots)
+                        .await
+                        .unwrap(),
+                }));
+            }
+            stats
+                .push(serde_json::to_value(db.path_visits(from, to, bots).await.unwrap()).unwrap());
+        }
+        serde_json::Value::Array(stats)
+    }
+
+    fn clear(db: &Database) {
+        let mut conn = db.pool.get().unwrap();
+        for table in ["visits", "daily_visits", "daily_totals"] {
+            let column = if table == "visits" { "instance" } else { "day" };
+            diesel::sql_query(format!(
+                "DELETE FROM {} WHERE {} >= '1990-01-01' AND {} < '1990-02-01'",
+                table, column, column
+            ))
+            .execute(&mut conn)
+            .unwrap();
+        }
+    }
+
+    /// Needs a migrated database in `TEST_DATABASE_URL`. Only the visits of January 1990 are
+    /// touched.
+    #[actix_web::test]
+    #[ignore]
+    async fn roll_up_keeps_the_totals() {
+        let db = Database::from_url(env::var("TEST_DATABASE_URL").unwrap());
+        clear(&db);
+
+        diesel::sql_query(
+            "INSERT INTO visits (visitor, path, instance, status, latency_ms, is_bot) VALUES \
+             ('a', '/', '1990-01-01 10:00', 200, 10, false), \
+             ('a', '/', '1990-01-01 11:00', 200, 20, false), \
+             ('a', '/blog', '1990-01-01 12:00', 200, 30, false), \
+             ('b', '/', '1990-01-01 13:00', NULL, NULL, false), \
+             ('b', '/missing', '1990-01-01 14:00', 404, 1, false), \
+             ('c', '/', '1990-01-02 09:00', 200, 5, true), \
+             ('a', '/blog', '1990-01-02 23:59', 200, 15, false), \
+             ('a', '/', '1990-01-03 00:00', 200, 40, false), \
+             ('d', '/blog', '1990-01-04 08:00', 304, 2, false)",
+        )
+        .execute(&mut db.pool.get().unwrap())
+        .unwrap();
+
+        let before = stats(&db).await;
+        let midnight = chrono::NaiveDate::from_ymd_opt(1990, 1, 3)
+            .unwrap()
+            .and_time(chrono::NaiveTime::MIN);
+
+        assert_eq!(db.roll_up_visits(midnight).unwrap(), 7);
+        assert_eq!(stats(&db).await, before);
+
+        // The days before the midnight are gone from the visits, so nothing is counted twice
+        assert_eq!(db.roll_up_visits(midnight).unwrap(), 0);
+        assert_eq!(stats(&db).await, before);
+
+        clear(&db);
     }
 }
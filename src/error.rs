@@ -8,14 +8,52 @@ pub enum Error {
     Database(#[from] diesel::result::Error),
     #[error("Database Pool error {0}")]
     DbPool(#[from] r2d2::Error),
+    #[error("IO error {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Fetching the image failed: {0}")]
+    Fetch(#[from] reqwest::Error),
+    #[error("Invalid multipart body: {0}")]
+    Multipart(#[from] actix_multipart::MultipartError),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("The upload exceeds the maximum size of {0} bytes")]
+    UploadTooLarge(usize),
+    #[error("Unsupported file type, expected a PNG, JPEG, WebP or GIF image")]
+    UnsupportedMediaType,
+    #[error("Invalid image: {0}")]
+    InvalidImage(String),
 }
 
 impl ResponseError for Error {
     fn status_code(&self) -> StatusCode {
-        StatusCode::INTERNAL_SERVER_ERROR
+        match self {
+            Self::Multipart(_) | Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::UploadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::InvalidImage(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Fetch(_) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).finish()
+        let status = self.status_code();
+        if status != StatusCode::INTERNAL_SERVER_ERROR {
+            HttpResponse::build(status).json(serde_json::json!({ "error": self.to_string() }))
+        } else {
+            HttpResponse::build(status).finish()
+        }
     }
 }
@@ -0,0 +1,111 @@
+use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
+
+/// Resolves the countries of visitors from a local database, without any network lookups
+pub struct GeoIp {
+    reader: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// Reads the MaxMind DB file in `GEOIP_DB_PATH`, such as GeoLite2 Country or City.
+    /// Countries are not resolved if it is not set.
+    pub fn from_env() -> Self {
+        let reader = std::env::var("GEOIP_DB_PATH").ok().map(|path| {
+            let buf = std::fs::read(&path)
+                .unwrap_or_else(|e| panic!("Failed to read GEOIP_DB_PATH {}: {}", path, e));
+            Reader::from_source(buf)
+                .unwrap_or_else(|e| panic!("{} is not a MaxMind DB file: {}", path, e))
+        });
+
+        Self { reader }
+    }
+
+    /// The ISO 3166-1 code of the country the address is in
+    pub fn country(&self, address: IpAddr) -> Option<String> {
+        let reader = self.reader.as_ref()?;
+        // IPv4 addresses are found the same way whether or not they are mapped to IPv6
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            v4 => v4,
+        };
+        if address.is_ipv6() && reader.metadata.ip_version == 4 {
+            return None;
+        }
+
+        let record = reader.lookup::<geoip2::Country>(address).ok()?;
+        let code = record
+            .country
+            .and_then(|c| c.iso_code)
+            .or_else(|| record.registered_country.and_then(|c| c.iso_code))?;
+
+        (code.len() == 2 && code.bytes().all(|b| b.is_ascii_uppercase())).then(|| code.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An IPv6 database with 24 bit records and the countries of 10.1.0.0/16 (FI),
+    /// 2001:db8::/32 (SE), 192.0.2.0/24 (only registered in DE) and 198.51.100.0/24 (invalid x1).
+    /// Generated with `python3 testdata/geoip-country.py`.
+    const DATABASE: &[u8] = include_bytes!("../testdata/geoip-country.mmdb");
+
+    fn geoip() -> GeoIp {
+        GeoIp {
+            reader: Some(Reader::from_source(DATABASE.to_vec()).unwrap()),
+        }
+    }
+
+    fn country(geoip: &GeoIp, address: &str) -> Option<String> {
+        geoip.country(address.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4_addresses_are_found() {
+        let geoip = geoip();
+        assert_eq!(country(&geoip, "10.1.2.3").as_deref(), Some("FI"));
+        assert_eq!(country(&geoip, "192.0.2.1").as_deref(), Some("DE"));
+    }
+
+    #[test]
+    fn ipv6_addresses_are_found() {
+        assert_eq!(country(&geoip(), "2001:db8::1").as_deref(), Some("SE"));
+    }
+
+    #[test]
+    fn mapped_ipv4_addresses_are_found() {
+        assert_eq!(country(&geoip(), "::ffff:10.1.2.3").as_deref(), Some("FI"));
+    }
+
+    #[test]
+    fn addresses_without_data_are_not_found() {
+        let geoip = geoip();
+        for address in ["10.2.0.1", "127.0.0.1", "2001:db9::1", "::1"] {
+            assert_eq!(country(&geoip, address), None, "{}", address);
+        }
+    }
+
+    #[test]
+    fn invalid_codes_are_left_out() {
+        assert_eq!(country(&geoip(), "198.51.100.1"), None);
+    }
+
+    #[test]
+    fn nothing_is_found_without_a_database() {
+        let geoip = GeoIp { reader: None };
+        assert_eq!(country(&geoip, "10.1.2.3"), None);
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(Reader::from_source(b"not a database".to_vec()).is_err());
+        assert!(Reader::from_source(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let half = DATABASE[..DATABASE.len() / 2].to_vec();
+        assert!(Reader::from_source(half).is_err());
+    }
+}
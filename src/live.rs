@@ -0,0 +1,224 @@
+use actix_web::web::Bytes;
+use futures_util::stream::Stream;
+use serde_derive::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Visitors count as active for this long after their last view
+pub const ACTIVE_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// How many of the latest views are shown
+const RECENT_VIEWS: usize = 10;
+/// The most views remembered, so that a flood of requests cannot exhaust the memory
+const MAX_VIEWS: usize = 10_000;
+/// How often an update is sent without new views, so that expired visitors leave the count
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// The most often the stats are computed and sent, however many views there are
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+/// The most clients following the stats at once
+const MAX_STREAMS: usize = 32;
+
+struct View {
+    seen: Instant,
+    time: chrono::NaiveDateTime,
+    visitor: String,
+    path: String,
+}
+
+#[derive(Serialize)]
+pub struct RecentView {
+    pub path: String,
+    pub time: String,
+}
+
+#[derive(Serialize)]
+pub struct LiveStats {
+    /// Distinct visitors during the last `ACTIVE_WINDOW`
+    pub active: usize,
+    /// The latest views, newest first
+    pub recent: Vec<RecentView>,
+}
+
+/// The views of the last few minutes, kept in memory for the live counter on the stats page.
+/// Visits by bots are not included.
+pub struct LiveVisitors {
+    views: Mutex<VecDeque<View>>,
+    /// Set by new views, so that the next update includes them
+    changed: AtomicBool,
+    /// The latest stats as a server-sent event, shared by every client
+    updates: watch::Sender<Bytes>,
+    streams: AtomicUsize,
+}
+
+/// Frees the place of a client when its stream is dropped
+struct StreamGuard(Arc<LiveVisitors>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.streams.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl LiveVisitors {
+    pub fn new() -> Self {
+        let live = Self {
+            views: Mutex::new(VecDeque::new()),
+            changed: AtomicBool::new(false),
+            updates: watch::channel(Bytes::new()).0,
+            streams: AtomicUsize::new(0),
+        };
+        live.update();
+        live
+    }
+
+    /// Starts the task that computes the stats for the clients once a second at most
+    pub fn start(self: Arc<Self>) {
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(UPDATE_INTERVAL);
+            let mut updated = Instant::now();
+            loop {
+                interval.tick().await;
+                if self.changed.swap(false, Ordering::SeqCst)
+                    || updated.elapsed() >= REFRESH_INTERVAL
+                {
+                    self.update();
+                    updated = Instant::now();
+                }
+            }
+        });
+    }
+
+    fn update(&self) {
+        let event = format!(
+            "data: {}\n\n",
+            serde_json::to_string(&self.stats()).unwrap()
+        );
+        self.updates.send_replace(Bytes::from(event));
+    }
+
+    pub fn record(&self, visitor: &str, path: &str) {
+        {
+            let mut views = self.views.lock().unwrap();
+            if views.len() >= MAX_VIEWS {
+                views.pop_front();
+            }
+            views.push_back(View {
+                seen: Instant::now(),
+                time: chrono::Local::now().naive_local(),
+                visitor: visitor.to_string(),
+                path: path.to_string(),
+            });
+        }
+
+        self.changed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn stats(&self) -> LiveStats {
+        let mut views = self.views.lock().unwrap();
+        while views
+            .front()
+            .is_some_and(|v| v.seen.elapsed() > ACTIVE_WINDOW)
+        {
+            views.pop_front();
+        }
+
+        LiveStats {
+            active: views
+                .iter()
+                .map(|v| v.visitor.as_str())
+                .collect::<HashSet<_>>()
+                .len(),
+            recent: views
+                .iter()
+                .rev()
+                .take(RECENT_VIEWS)
+                .map(|v| RecentView {
+                    path: v.path.clone(),
+                    time: v.time.format("%T").to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Server-sent events with the current stats, sent after new views and every
+    /// `REFRESH_INTERVAL`. None if there are already `MAX_STREAMS` clients.
+    pub fn events(self: Arc<Self>) -> Option<impl Stream<Item = Result<Bytes, actix_web::Error>>> {
+        self.streams
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < MAX_STREAMS).then_some(n + 1)
+            })
+            .ok()?;
+        let updates = self.updates.subscribe();
+        let guard = StreamGuard(self);
+
+        Some(futures_util::stream::unfold(
+            (guard, updates, true),
+            |(guard, mut updates, first)| async move {
+                // Only the latest stats are sent to clients that fall behind
+                if !first && updates.changed().await.is_err() {
+                    return None;
+                }
+
+                let event = updates.borrow_and_update().clone();
+                Some((Ok(event), (guard, updates, false)))
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt as _;
+
+    fn stats(event: Bytes) -> serde_json::Value {
+        let event = std::str::from_utf8(&event).unwrap();
+        serde_json::from_str(event.strip_prefix("data: ").unwrap().trim_end()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn streams_follow_the_updates() {
+        let live = Arc::new(LiveVisitors::new());
+        let mut events = Box::pin(live.clone().events().unwrap());
+        assert_eq!(stats(events.next().await.unwrap().unwrap())["active"], 0);
+
+        live.record("a", "/");
+        live.record("b", "/blog");
+        live.record("a", "/gallery");
+        live.update();
+
+        let update = stats(events.next().await.unwrap().unwrap());
+        assert_eq!(update["active"], 2);
+        assert_eq!(update["recent"][0]["path"], "/gallery");
+        assert_eq!(update["recent"].as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn views_are_sent_by_the_next_update() {
+        let live = LiveVisitors::new();
+        let updates = live.updates.subscribe();
+
+        for _ in 0..100 {
+            live.record("a", "/");
+        }
+        assert!(!updates.has_changed().unwrap());
+
+        live.update();
+        assert!(updates.has_changed().unwrap());
+        assert_eq!(stats(updates.borrow().clone())["active"], 1);
+    }
+
+    #[test]
+    fn streams_are_limited() {
+        let live = Arc::new(LiveVisitors::new());
+        let mut streams = (0..MAX_STREAMS)
+            .map(|_| live.clone().events().unwrap())
+            .collect::<Vec<_>>();
+        assert!(live.clone().events().is_none());
+
+        streams.pop();
+        assert!(live.clone().events().is_some());
+    }
+}
@@ -1,21 +1,38 @@
 #![feature(lazy_cell)]
 
+mod admin;
+mod auth;
+mod bots;
+mod charts;
 mod database;
 mod error;
+mod geoip;
+mod live;
+mod metadata;
 mod models;
 mod payloadverifier;
+mod resizer;
 mod schema;
+mod thumbnail;
+mod upload;
+mod useragent;
 mod visitcounter;
 
 use crate::database::Database;
 use crate::models::*;
+use crate::resizer::{ImageCache, ResizeQuery};
+use crate::upload::{UploadConfig, UploadFields, UploadResult, UploadedImage};
 use actix_files::Files;
 use actix_multipart::Multipart;
 use actix_rt::time;
-use actix_web::{dev, guard, middleware, web, App, Error, HttpResponse, HttpServer, Result};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::{header, StatusCode};
+use actix_web::{
+    delete, dev, get, guard, middleware, patch, post, web, App, Error, HttpRequest, HttpResponse,
+    HttpServer, Result,
+};
 use futures_util::stream::StreamExt as _;
 use hmac::{Hmac, Mac};
-use rand::distributions::Alphanumeric;
 use rand::Rng;
 use serde_derive::Serialize;
 use sha2::Sha256;
@@ -25,18 +42,34 @@ use std::process::Command;
 use std::sync::Mutex;
 use tera::Tera;
 
-#[macro_use]
-extern crate actix_web;
-
 #[macro_use]
 extern crate diesel;
 
+/// The commit that is currently checked out
+fn git_revision() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
 async fn update(
     payload: web::Json<UpdatePayload>,
     tmpl: web::Data<Mutex<Tera>>,
     blogcontext: web::Data<Mutex<BlogContext>>,
+    db: web::Data<Database>,
 ) -> Result<HttpResponse, Error> {
-    Command::new("git").arg("pull").output().unwrap();
+    let started = chrono::Local::now().naive_local();
+    let pull = Command::new("git").arg("pull").output().unwrap();
+    let mut output = format!(
+        "{}{}",
+        String::from_utf8_lossy(&pull.stdout),
+        String::from_utf8_lossy(&pull.stderr)
+    );
 
     if let Some(workflow) = &payload.workflow_run {
         let token = std::env::var("GITHUB_TOKEN").expect("No GITHUB_TOKEN");
@@ -68,16 +101,30 @@ async fn update(
             let mut content = std::io::Cursor::new(res.bytes().await.unwrap());
             std::io::copy(&mut content, &mut zipfile).unwrap();
 
-            Command::new("unzip")
+            let unzip = Command::new("unzip")
                 .args(["-o", "build.zip"])
                 .output()
                 .unwrap();
+            output.push_str(&String::from_utf8_lossy(&unzip.stdout));
 
             Command::new("chmod")
                 .args(["+x", "lajp_fi-rs"])
                 .output()
                 .unwrap();
 
+            // The history is only informational, so a failure does not stop the update
+            if let Err(e) = db
+                .new_deployment(NewDeployment {
+                    started,
+                    kind: "restart".to_string(),
+                    revision: git_revision(),
+                    output,
+                })
+                .await
+            {
+                log::error!("Failed to record the deployment: {}", e);
+            }
+
             std::thread::spawn(|| {
                 // A very, very cursed way of restarting
                 std::thread::sleep(std::time::Duration::from_secs(2));
@@ -90,6 +137,19 @@ async fn update(
     tmpl.lock().unwrap().full_reload().unwrap();
     blogcontext.lock().unwrap().reload();
 
+    // The history is only informational, so a failure does not stop the update
+    if let Err(e) = db
+        .new_deployment(NewDeployment {
+            started,
+            kind: "reload".to_string(),
+            revision: git_revision(),
+            output,
+        })
+        .await
+    {
+        log::error!("Failed to record the deployment: {}", e);
+    }
+
     Ok(HttpResponse::Ok().body("Update done! Reloading files now!"))
 }
 
@@ -179,98 +239,745 @@ async fn blogarticle(
     Ok(HttpResponse::Ok().content_type("text/html").body(res))
 }
 
-#[post("/gallery")]
-async fn add_to_gallery(
+/// Validates, strips and stores a single image, returning the existing one for duplicates
+async fn store_image(
+    content: Vec<u8>,
+    filename: Option<String>,
+    fields: UploadFields,
+    conn: &dev::ConnectionInfo,
+    db: &Database,
+    imagegallery: &Mutex<ImageGallery>,
+    uploadconfig: &UploadConfig,
+) -> Result<UploadedImage, Error> {
+    let kind = upload::validate(&content)?;
+    let stripped = web::block(move || metadata::strip(content, kind)).await??;
+    let content = stripped.content;
+    let name = upload::content_name(&content, stripped.kind);
+    let hash = upload::content_hash(&content);
+
+    let existing = imagegallery
+        .lock()
+        .unwrap()
+        .images
+        .iter()
+        .find(|i| i.hash == hash)
+        .cloned();
+
+    if let Some(img) = existing {
+        return Ok(UploadedImage {
+            url: format!("{}://{}{}", conn.scheme(), conn.host(), &img.path),
+            name: img.name,
+            original_name: img.original_name,
+            duplicate: true,
+        });
+    }
+
+    let size =
+        imagesize::blob_size(&content).map_err(|e| error::Error::InvalidImage(e.to_string()))?;
+
+    let path = format!("./static/gallery/{}", &name);
+    let stored = async {
+        File::create(&path)?.write_all(content.as_slice())?;
+
+        if let Some(metadata) = stripped.metadata.filter(|_| uploadconfig.keep_metadata) {
+            db.new_image_metadata(ImageMetadata {
+                name: name.clone(),
+                metadata,
+                created: chrono::Local::now().naive_local(),
+            })
+            .await?;
+        }
+
+        db.new_image(NewImage {
+            path: path[1..].to_string(), // Strip the redundant "." from the start
+            original_name: filename.clone(),
+            caption: None,
+            album: fields.album,
+            location: fields.location,
+            uploaded: chrono::Local::now().naive_local(),
+            width: size.width as i32,
+            height: size.height as i32,
+            hash,
+        })
+        .await
+    }
+    .await;
+
+    // A file left behind without a row would be taken for an existing image by the import
+    let row = match stored {
+        Ok(row) => row,
+        Err(e) => {
+            let _ = std::fs::remove_file(&path);
+            return Err(e.into());
+        }
+    };
+    let img = Image::from(row);
+    let url = format!("{}://{}{}", conn.scheme(), conn.host(), &img.path);
+
+    let (img, changes) = web::block(move || {
+        let mut img = img;
+        let changes = img.prepare();
+        (img, changes)
+    })
+    .await?;
+
+    if changes.placeholder.is_some() {
+        db.update_image(img.id, changes).await?;
+    }
+
+    imagegallery.lock().unwrap().add_image(img);
+
+    Ok(UploadedImage {
+        url,
+        name,
+        original_name: filename,
+        duplicate: false,
+    })
+}
+
+enum UploadSource {
+    File(Option<String>, Result<Vec<u8>, error::Error>),
+    Url(String),
+}
+
+/// Stores the files and URLs of a multipart upload. Returns the status code
+/// to respond with along with the outcome of each of them.
+async fn upload_images(
     mut payload: Multipart,
-    imagegallery: web::Data<Mutex<ImageGallery>>,
-) -> Result<HttpResponse, Error> {
-    let mut filename = String::new();
-    let mut content = Vec::<u8>::new();
+    conn: &dev::ConnectionInfo,
+    db: &Database,
+    imagegallery: &Mutex<ImageGallery>,
+    uploadconfig: &UploadConfig,
+) -> Result<(StatusCode, Vec<UploadResult>), Error> {
+    let mut fields = UploadFields::default();
+    let mut sources = Vec::new();
 
     while let Some(item) = payload.next().await {
-        let mut field = item?;
+        let mut field = item.map_err(error::Error::from)?;
+        if (field.name() == "file" || field.name() == "url")
+            && sources.len() >= upload::MAX_FILES_PER_UPLOAD
+        {
+            return Err(error::Error::BadRequest(format!(
+                "At most {} files can be uploaded at once",
+                upload::MAX_FILES_PER_UPLOAD
+            ))
+            .into());
+        }
+
         if field.name() == "file" {
             let cd = field.content_disposition();
-            filename = cd.get_filename().unwrap().to_string();
+            let filename = cd.get_filename().and_then(upload::sanitize_filename);
 
-            while let Some(chunk) = field.next().await {
-                content.append(&mut chunk?.to_vec());
+            let content = upload::read_field(&mut field, uploadconfig.max_size).await;
+            // Forms send an empty file field when no file is chosen
+            if filename.is_none() && content.as_ref().is_ok_and(|c| c.is_empty()) {
+                continue;
             }
+            sources.push(UploadSource::File(filename, content));
+        } else if field.name() == "url" {
+            let value = upload::read_field(&mut field, 2048).await?;
+            let url = String::from_utf8_lossy(&value).trim().to_string();
+            if !url.is_empty() {
+                sources.push(UploadSource::Url(url));
+            }
+        } else if field.name() == "album" {
+            let value = upload::read_field(&mut field, 256).await?;
+            fields.album = upload::sanitize_album(&String::from_utf8_lossy(&value))?;
+        } else if ["latitude", "longitude", "country", "round", "game"].contains(&field.name()) {
+            let name = field.name().to_string();
+            let value = upload::read_field(&mut field, 256).await?;
+            upload::set_location_field(
+                &mut fields.location,
+                &name,
+                String::from_utf8_lossy(&value).trim(),
+            )?;
         }
     }
 
-    if !content.is_empty() {
-        let path = format!("./static/gallery/{}", &filename);
-        let mut img = Image::new(&path);
+    upload::validate_location(
+        fields.location.latitude,
+        fields.location.longitude,
+        fields.location.round,
+    )?;
+
+    if sources.is_empty() {
+        return Err(error::Error::BadRequest("No files or URLs were given".to_string()).into());
+    }
+
+    let mut results = Vec::new();
+    let mut first_error = None;
+
+    for source in sources {
+        let (source, result) = match source {
+            UploadSource::File(filename, content) => {
+                let result = match content {
+                    Ok(content) => Ok((content, filename.clone())),
+                    Err(e) => Err(e.into()),
+                };
+                (filename, result)
+            }
+            UploadSource::Url(url) => {
+                let result = upload::fetch(&url, uploadconfig.max_size, uploadconfig.fetch_timeout)
+                    .await
+                    .map(|content| (content, upload::url_filename(&url)))
+                    .map_err(Error::from);
+                (Some(url), result)
+            }
+        };
+
+        let result = match result {
+            Ok((content, filename)) => {
+                store_image(
+                    content,
+                    filename,
+                    fields.clone(),
+                    conn,
+                    db,
+                    imagegallery,
+                    uploadconfig,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
 
-        while imagegallery.lock().unwrap().images.contains(&img) {
-            let mut ext = format!(".{}", &img.name.rsplit('.').next().unwrap_or_default());
-            if ext.len() == 1 {
-                ext.clear();
+        results.push(match result {
+            Ok(image) => UploadResult {
+                source,
+                image: Some(image),
+                error: None,
+            },
+            Err(e) => {
+                let status = e.as_response_error().status_code();
+                first_error.get_or_insert(status);
+                UploadResult {
+                    source,
+                    image: None,
+                    error: Some(e.to_string()),
+                }
             }
+        });
+    }
+
+    // A failed single file upload keeps the status code of its error
+    let status = match first_error {
+        Some(status) if results.iter().all(|r| r.image.is_none()) => status,
+        _ => StatusCode::OK,
+    };
+
+    Ok((status, results))
+}
 
-            let c = rand::thread_rng().sample(Alphanumeric) as char;
-            img.path.truncate(img.path.len() - ext.len());
-            img.name.truncate(img.name.len() - ext.len());
-            img.path.push(c);
-            img.path.push_str(&ext);
-            img.name.push(c);
-            img.name.push_str(&ext);
+#[post("/gallery")]
+async fn add_to_gallery(
+    payload: Multipart,
+    conn: dev::ConnectionInfo,
+    db: web::Data<Database>,
+    imagegallery: web::Data<Mutex<ImageGallery>>,
+    uploadconfig: web::Data<UploadConfig>,
+) -> Result<HttpResponse, Error> {
+    let (status, results) =
+        upload_images(payload, &conn, &db, &imagegallery, &uploadconfig).await?;
+
+    Ok(HttpResponse::build(status).json(serde_json::json!({ "results": results })))
+}
+
+/// Removes the image from the database, the gallery and the disk
+async fn remove_gallery_image(
+    name: &str,
+    db: &Database,
+    imagegallery: &Mutex<ImageGallery>,
+) -> Result<(), error::Error> {
+    let img = imagegallery
+        .lock()
+        .unwrap()
+        .find(name)
+        .cloned()
+        .ok_or_else(|| error::Error::NotFound(format!("No image called {}", name)))?;
+
+    db.delete_image(img.id).await?;
+    imagegallery.lock().unwrap().remove_image(name);
+
+    std::fs::remove_file(format!(".{}", &img.path))?;
+    thumbnail::remove(name)?;
+
+    Ok(())
+}
+
+#[delete("/gallery/{name}")]
+async fn delete_from_gallery(
+    path: web::Path<(String,)>,
+    db: web::Data<Database>,
+    imagegallery: web::Data<Mutex<ImageGallery>>,
+) -> Result<HttpResponse, Error> {
+    remove_gallery_image(&path.0, &db, &imagegallery).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Applies the patch to the image, renaming its files if the name changes
+async fn update_gallery_image(
+    name: &str,
+    patch: ImagePatch,
+    db: &Database,
+    imagegallery: &Mutex<ImageGallery>,
+) -> Result<Image, error::Error> {
+    let img = imagegallery
+        .lock()
+        .unwrap()
+        .find(name)
+        .cloned()
+        .ok_or_else(|| error::Error::NotFound(format!("No image called {}", name)))?;
+
+    if patch.latitude.is_some() != patch.longitude.is_some() {
+        return Err(error::Error::BadRequest(
+            "Both the latitude and the longitude have to be given".to_string(),
+        ));
+    }
+    upload::validate_location(
+        patch.latitude.flatten(),
+        patch.longitude.flatten(),
+        patch.round.flatten(),
+    )?;
+
+    let mut changes = ImageChanges {
+        caption: patch.caption.map(|c| Some(c).filter(|c| !c.is_empty())),
+        alt: patch.alt.map(|a| Some(a).filter(|a| !a.is_empty())),
+        album: patch
+            .album
+            .map(|a| upload::sanitize_album(&a))
+            .transpose()?,
+        latitude: patch.latitude,
+        longitude: patch.longitude,
+        country: patch
+            .country
+            .map(|c| upload::sanitize_country(&c))
+            .transpose()?,
+        round: patch.round,
+        game: patch.game.map(|g| upload::sanitize_game(&g)).transpose()?,
+        ..Default::default()
+    };
+
+    let new_name = match patch.name {
+        Some(new_name) => {
+            let mut new_name = upload::sanitize_filename(&new_name)
+                .ok_or_else(|| error::Error::BadRequest("Invalid name".to_string()))?;
+
+            if let Some((_, ext)) = name.rsplit_once('.') {
+                if !new_name.ends_with(&format!(".{}", ext)) {
+                    new_name = format!("{}.{}", new_name, ext);
+                }
+            }
+
+            Some(new_name).filter(|n| n != name)
         }
+        None => None,
+    };
+
+    if let Some(new_name) = &new_name {
+        let new_path = format!("./static/gallery/{}", new_name);
+        if std::path::Path::new(&new_path).exists() {
+            return Err(error::Error::Conflict(format!(
+                "An image called {} already exists",
+                new_name
+            )));
+        }
+
+        changes.path = Some(new_path[1..].to_string());
+    }
 
-        let mut newfile = File::create(format!(".{}", &img.path))?;
-        newfile.write_all(content.as_slice())?;
+    if changes.is_empty() {
+        return Ok(img);
+    }
 
-        imagegallery.lock().unwrap().add_image(img);
+    // The files are renamed first and moved back if the database could not be updated,
+    // so that the files and the database always agree on the name
+    if let Some(new_name) = &new_name {
+        let old_path = format!(".{}", &img.path);
+        let new_path = format!("./static/gallery/{}", new_name);
 
-        return Ok(HttpResponse::Ok().finish());
+        std::fs::rename(&old_path, &new_path)?;
+        if let Err(e) = thumbnail::rename(name, new_name) {
+            let _ = std::fs::rename(&new_path, &old_path);
+            return Err(e.into());
+        }
     }
 
-    Err(actix_web::error::ErrorBadRequest("You have done stupiding"))
+    let row = match db.update_image(img.id, changes).await {
+        Ok(row) => row,
+        Err(e) => {
+            if let Some(new_name) = &new_name {
+                let _ = std::fs::rename(
+                    format!("./static/gallery/{}", new_name),
+                    format!(".{}", &img.path),
+                );
+                let _ = thumbnail::rename(new_name, name);
+            }
+            return Err(e);
+        }
+    };
+
+    let img = Image::from(row);
+    imagegallery
+        .lock()
+        .unwrap()
+        .replace_image(name, img.clone());
+
+    Ok(img)
+}
+
+#[patch("/gallery/{name}")]
+async fn edit_gallery_image(
+    path: web::Path<(String,)>,
+    patch: web::Json<ImagePatch>,
+    db: web::Data<Database>,
+    imagegallery: web::Data<Mutex<ImageGallery>>,
+) -> Result<HttpResponse, Error> {
+    let img = update_gallery_image(&path.0, patch.into_inner(), &db, &imagegallery).await?;
+
+    Ok(HttpResponse::Ok().json(img))
+}
+
+#[get("/gallery.json")]
+async fn gallery_json(imagegallery: web::Data<Mutex<ImageGallery>>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(&imagegallery.lock().unwrap().images))
+}
+
+const GALLERY_PAGE_SIZE: usize = 24;
+const GALLERY_SEED_COOKIE: &str = "gallery_seed";
+
+#[derive(Serialize)]
+struct GalleryContext {
+    album: Option<String>,
+    albums: Vec<Album>,
+    images: Vec<Image>,
+    order: GalleryOrder,
+    seed: u64,
+    page: usize,
+    pages: usize,
+}
+
+fn render_gallery(
+    req: &HttpRequest,
+    tmpl: &Mutex<Tera>,
+    imagegallery: &Mutex<ImageGallery>,
+    query: GalleryQuery,
+    album: Option<String>,
+) -> Result<HttpResponse, Error> {
+    // The seed is kept in a cookie so that the shuffled order survives paging and reloads
+    let cookie_seed = req
+        .cookie(GALLERY_SEED_COOKIE)
+        .and_then(|c| c.value().parse::<u64>().ok());
+    let seed = query
+        .seed
+        .or(cookie_seed)
+        .unwrap_or_else(|| rand::thread_rng().gen());
+
+    let (albums, images) = {
+        let imagegallery = imagegallery.lock().unwrap();
+        (
+            imagegallery.albums(),
+            imagegallery.ordered(query.order, seed),
+        )
+    };
+
+    let images = images
+        .into_iter()
+        .filter(|i| album.is_none() || i.album == album)
+        .collect::<Vec<_>>();
+
+    if images.is_empty() && album.is_some() {
+        return Err(actix_web::error::ErrorNotFound("No such album"));
+    }
+
+    let page_count = images.len().div_ceil(GALLERY_PAGE_SIZE).max(1);
+    let page = query.page.unwrap_or(1).clamp(1, page_count);
+
+    let gallerycontext = GalleryContext {
+        album,
+        albums,
+        images: images
+            .into_iter()
+            .skip((page - 1) * GALLERY_PAGE_SIZE)
+            .take(GALLERY_PAGE_SIZE)
+            .collect(),
+        order: query.order,
+        seed,
+        page,
+        pages: page_count,
+    };
+
+    let res = tmpl
+        .lock()
+        .unwrap()
+        .render(
+            "gallery.html",
+            &tera::Context::from_serialize(gallerycontext).unwrap(),
+        )
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Template error"))?;
+
+    let mut response = HttpResponse::Ok();
+    if cookie_seed != Some(seed) {
+        response.cookie(
+            Cookie::build(GALLERY_SEED_COOKIE, seed.to_string())
+                .path("/gallery")
+                .same_site(SameSite::Lax)
+                .finish(),
+        );
+    }
+    Ok(response.content_type("text/html").body(res))
 }
 
 #[get("/gallery")]
 async fn gallery(
+    req: HttpRequest,
     tmpl: web::Data<Mutex<Tera>>,
     imagegallery: web::Data<Mutex<ImageGallery>>,
+    query: web::Query<GalleryQuery>,
 ) -> Result<HttpResponse, Error> {
-    imagegallery.lock().unwrap().shuffle();
+    render_gallery(&req, &tmpl, &imagegallery, query.into_inner(), None)
+}
+
+#[derive(Serialize)]
+struct MapContext {
+    country: Option<String>,
+    countries: Vec<Country>,
+}
+
+#[get("/gallery/map")]
+async fn gallery_map(
+    tmpl: web::Data<Mutex<Tera>>,
+    imagegallery: web::Data<Mutex<ImageGallery>>,
+    query: web::Query<MapQuery>,
+) -> Result<HttpResponse, Error> {
+    let mapcontext = MapContext {
+        country: query
+            .into_inner()
+            .country
+            .map(|c| upload::sanitize_country(&c))
+            .transpose()?
+            .flatten(),
+        countries: imagegallery.lock().unwrap().countries(),
+    };
+
     let res = tmpl
         .lock()
         .unwrap()
         .render(
-            "gallery.html",
-            &tera::Context::from_serialize(&imagegallery).unwrap(),
+            "gallery-map.html",
+            &tera::Context::from_serialize(mapcontext).unwrap(),
         )
         .map_err(|_| actix_web::error::ErrorInternalServerError("Template error"))?;
     Ok(HttpResponse::Ok().content_type("text/html").body(res))
 }
 
+#[get("/gallery/map.json")]
+async fn gallery_map_json(
+    imagegallery: web::Data<Mutex<ImageGallery>>,
+    query: web::Query<MapQuery>,
+) -> Result<HttpResponse, Error> {
+    let country = query
+        .into_inner()
+        .country
+        .map(|c| upload::sanitize_country(&c))
+        .transpose()?
+        .flatten();
+
+    Ok(HttpResponse::Ok().json(imagegallery.lock().unwrap().markers(country.as_deref())))
+}
+
+#[get("/gallery/{album}")]
+async fn gallery_album(
+    req: HttpRequest,
+    tmpl: web::Data<Mutex<Tera>>,
+    imagegallery: web::Data<Mutex<ImageGallery>>,
+    query: web::Query<GalleryQuery>,
+    path: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    render_gallery(
+        &req,
+        &tmpl,
+        &imagegallery,
+        query.into_inner(),
+        Some(path.into_inner().0),
+    )
+}
+
 #[derive(Serialize)]
-struct VisitContext {
-    pages: Vec<Visits>,
+struct StatsContext {
+    from: chrono::NaiveDate,
+    to: chrono::NaiveDate,
+    interval: StatsInterval,
+    path: Option<String>,
+    bots: bool,
+    totals: VisitTotals,
+    periods: Vec<PeriodVisits>,
+    pages: Vec<PathVisits>,
+    referrers: Vec<ReferrerVisits>,
+    browsers: Vec<BrowserVisits>,
+    countries: Vec<CountryVisits>,
+    /// The referrers and browsers are only known for visits from this many days
+    retention_days: Option<i64>,
+}
+
+const STATS_TOP_REFERRERS: i64 = 20;
+
+async fn stats_context(db: &Database, query: &StatsQuery) -> Result<StatsContext, Error> {
+    let (from, to) = query.range()?;
+    let (start, end) = query.timestamps()?;
+    let path = query.path();
+    let bots = query.bots;
+
+    Ok(StatsContext {
+        from,
+        to,
+        interval: query.interval,
+        path: path.map(String::from),
+        bots,
+        totals: db.visit_totals(start, end, path, bots).await?,
+        periods: db
+            .visits_over_time(query.interval, start, end, path, bots)
+            .await?,
+        pages: db.path_visits(start, end, bots).await?,
+        referrers: db
+            .top_referrers(start, end, path, bots, STATS_TOP_REFERRERS)
+            .await?,
+        browsers: BrowserVisits::from_user_agents(
+            db.user_agent_visits(start, end, path, bots).await?,
+        ),
+        countries: db.country_visits(start, end, path, bots).await?,
+        retention_days: *visitcounter::RETENTION_DAYS,
+    })
 }
 
 #[get("/stats")]
 async fn stats(
     db: web::Data<Database>,
     tmpl: web::Data<Mutex<Tera>>,
+    query: web::Query<StatsQuery>,
 ) -> Result<HttpResponse, Error> {
-    let mut visits = db.visits_per_path().await?;
-    visits.sort_by_key(|v| -v.visit_count);
-    let visitcontext = VisitContext { pages: visits };
+    let statscontext = stats_context(&db, &query).await?;
+    let (start, end) = query.timestamps()?;
+    let path_visits = db
+        .path_visits_over_time(query.interval, start, end, None, query.bots)
+        .await?;
+
+    // The charts are only part of the page, not of the exported stats
+    let mut context = tera::Context::from_serialize(&statscontext).unwrap();
+    context.insert(
+        "chart",
+        &charts::traffic_chart(&statscontext.periods, query.interval),
+    );
+    context.insert(
+        "sparklines",
+        &charts::sparklines(&statscontext.periods, &path_visits),
+    );
 
     let res = tmpl
         .lock()
         .unwrap()
-        .render(
-            "stats.html",
-            &tera::Context::from_serialize(visitcontext).unwrap(),
-        )
+        .render("stats.html", &context)
         .map_err(|_| actix_web::error::ErrorInternalServerError("Template error"))?;
     Ok(HttpResponse::Ok().content_type("text/html").body(res))
 }
 
+#[get("/stats.json")]
+async fn stats_json(
+    db: web::Data<Database>,
+    query: web::Query<StatsQuery>,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(stats_context(&db, &query).await?))
+}
+
+#[get("/stats/live")]
+async fn stats_live(livevisitors: web::Data<live::LiveVisitors>) -> HttpResponse {
+    match livevisitors.into_inner().events() {
+        Some(events) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .streaming(events),
+        None => HttpResponse::ServiceUnavailable().body("Too many clients following the stats"),
+    }
+}
+
+/// Quotes the field if it contains anything special to CSV
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// The views and visitors of each page in each period, one row per page and period with visits
+#[get("/stats.csv")]
+async fn stats_csv(
+    db: web::Data<Database>,
+    query: web::Query<StatsQuery>,
+) -> Result<HttpResponse, Error> {
+    let (from, to) = query.range()?;
+    let (start, end) = query.timestamps()?;
+    let rows = db
+        .path_visits_over_time(query.interval, start, end, query.path(), query.bots)
+        .await?;
+
+    let mut csv = String::from("period,path,views,visitors\r\n");
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{}\r\n",
+            row.period,
+            csv_field(&row.path),
+            row.views,
+            row.visitors
+        ));
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"stats-{}-{}.csv\"", from, to),
+        ))
+        .body(csv))
+}
+
+#[get("/img/{path:.*}")]
+async fn resized_image(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    query: web::Query<ResizeQuery>,
+    imagecache: web::Data<ImageCache>,
+) -> Result<HttpResponse, Error> {
+    let imagecache = imagecache.into_inner();
+    let resized =
+        web::block(move || imagecache.get(&path.into_inner().0, &query.into_inner())).await??;
+
+    let cached = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.split(',').any(|etag| etag.trim() == resized.etag));
+
+    let mut response = if cached {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    // The URL stays the same when the source image is replaced, so the cached copy is only
+    // kept for a while and revalidated against the ETag after that
+    response
+        .insert_header((header::ETAG, resized.etag))
+        .insert_header((header::CACHE_CONTROL, "public, max-age=3600"));
+
+    if cached {
+        return Ok(response.finish());
+    }
+
+    Ok(response
+        .content_type(resized.format.content_type())
+        .body(std::fs::read(&resized.path)?))
+}
+
 #[get("/{file}.txt")]
 async fn txtfiles(path: web::Path<(String,)>) -> Result<HttpResponse, Error> {
     let content = std::fs::read_to_string(format!("static/{}.txt", &path.0))
@@ -290,13 +997,57 @@ async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
     env_logger::init();
 
+    if std::env::args().nth(1).as_deref() == Some("hash-password") {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        println!(
+            "{}",
+            admin::hash_password(password.trim_end_matches(['\r', '\n']))
+        );
+        return Ok(());
+    }
+
     let blogcontext = web::Data::new(Mutex::new(BlogContext::new("./templates/blog/")));
-    let imagegallery = web::Data::new(Mutex::new(ImageGallery::new("./static/gallery/")));
     let activity: web::Data<Mutex<Option<Activity>>> = web::Data::new(Mutex::new(None));
     let activity_clone = activity.clone();
 
     let database = Database::new();
 
+    if std::env::args().nth(1).as_deref() == Some("token") {
+        let args = std::env::args().skip(2).collect::<Vec<_>>();
+        if let Err(e) = auth::command(&args, &database).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut loadedgallery = ImageGallery::new("./static/gallery/", &database)
+        .await
+        .expect("Failed to load the image gallery");
+
+    if std::env::args().nth(1).as_deref() == Some("import-gallery") {
+        let imported = loadedgallery
+            .import(&database)
+            .await
+            .expect("Failed to import the image gallery");
+        println!("Imported {} images", imported);
+        return Ok(());
+    }
+
+    let imagegallery = web::Data::new(Mutex::new(loadedgallery));
+    let uploadconfig = web::Data::new(UploadConfig::from_env());
+    let imagecache = web::Data::new(ImageCache::new("./static/"));
+    let adminconfig = admin::AdminConfig::from_env();
+    let loginattempts = web::Data::new(admin::LoginAttempts::default());
+    let visitors = std::sync::Arc::new(visitcounter::Visitors::from_env());
+    let botdetector = std::sync::Arc::new(bots::BotDetector::from_env());
+    let (recorder, recording) = visitcounter::VisitRecorder::start(database.clone());
+    visitcounter::spawn_roll_up(database.clone());
+    let livevisitors = std::sync::Arc::new(live::LiveVisitors::new());
+    livevisitors.clone().start();
+    let geoip = std::sync::Arc::new(geoip::GeoIp::from_env());
+
     actix_rt::spawn(async move {
         let mut interval = time::interval(std::time::Duration::from_secs(30));
         loop {
@@ -305,26 +1056,35 @@ async fn main() -> std::io::Result<()> {
         }
     });
 
-    HttpServer::new(move || {
-        let tera = Mutex::new(Tera::new("templates/**/*").unwrap());
+    // Shared between the workers so that reloading it affects all of them
+    let tera = web::Data::new(Mutex::new(Tera::new("templates/**/*").unwrap()));
 
+    HttpServer::new(move || {
         let secret = std::env::var("GITHUB_SECRET").expect("No GITHUB_SECRET");
         let sbytes = secret.as_bytes();
 
-        let galleryauth = format!(
-            "Bearer {}",
-            std::env::var("GALLERY_TOKEN").expect("NO GALLERY_TOKEN")
-        );
-
         App::new()
-            .app_data(web::Data::new(tera))
+            .app_data(web::Data::clone(&tera))
             .app_data(web::Data::clone(&blogcontext))
             .app_data(web::Data::clone(&imagegallery))
             .app_data(web::Data::clone(&activity))
+            .app_data(web::Data::clone(&uploadconfig))
+            .app_data(web::Data::clone(&imagecache))
+            .app_data(web::Data::new(adminconfig.clone()))
+            .app_data(web::Data::clone(&loginattempts))
             .app_data(web::Data::new(database.clone()))
+            .app_data(web::Data::new(recorder.clone()))
+            .app_data(web::Data::from(livevisitors.clone()))
             .service(Files::new("/static", "./static"))
             .service(checkhealth)
+            .service(resized_image)
             .service(stats)
+            .service(stats_json)
+            .service(stats_csv)
+            .service(stats_live)
+            .service(
+                web::scope("/admin").configure(|cfg| admin::configure(cfg, adminconfig.clone())),
+            )
             .service(
                 web::scope("")
                     .wrap(middleware::Logger::new(
@@ -332,10 +1092,19 @@ async fn main() -> std::io::Result<()> {
                     ))
                     .wrap(visitcounter::VisitCounter {
                         db: database.clone(),
+                        visitors: visitors.clone(),
+                        bots: botdetector.clone(),
+                        recorder: recorder.clone(),
+                        live: livevisitors.clone(),
+                        geoip: geoip.clone(),
                     })
                     .service(index)
                     .service(blogindex)
                     .service(gallery)
+                    .service(gallery_json)
+                    .service(gallery_map)
+                    .service(gallery_map_json)
+                    .service(gallery_album)
                     .service(txtfiles)
                     .service(whatsmyip)
                     .service(pages)
@@ -349,15 +1118,58 @@ async fn main() -> std::io::Result<()> {
                     )
                     .service(
                         web::scope("")
-                            .guard(guard::Header(
-                                "Authorization",
-                                Box::leak(galleryauth.into_boxed_str()),
-                            ))
-                            .service(add_to_gallery),
+                            // Requests without credentials fall through to a 404 like before
+                            .guard(guard::fn_guard(|ctx| {
+                                ctx.head().headers().contains_key(header::AUTHORIZATION)
+                            }))
+                            .wrap(auth::RequireScope {
+                                db: database.clone(),
+                                scope: auth::Scope::GalleryWrite,
+                            })
+                            .service(add_to_gallery)
+                            .service(delete_from_gallery)
+                            .service(edit_gallery_image),
                     ),
             )
     })
     .bind(("127.0.0.1", 6900))?
     .run()
-    .await
+    .await?;
+
+    // The workers and with them the recorders are gone, so this stores the remaining visits
+    if let Err(e) = recording.await {
+        log::error!("Storing the visits failed: {}", e);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_csv_fields_are_kept() {
+        assert_eq!(csv_field("/blog/x200"), "/blog/x200");
+        assert_eq!(csv_field(""), "");
+        assert!(matches!(csv_field("/"), std::borrow::Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn csv_fields_with_commas_are_quoted() {
+        assert_eq!(csv_field("/stats?from=a,b"), "\"/stats?from=a,b\"");
+    }
+
+    #[test]
+    fn csv_field_quotes_are_doubled() {
+        assert_eq!(csv_field("/say\"hi\""), "\"/say\"\"hi\"\"\"");
+        assert_eq!(csv_field("\""), "\"\"\"\"");
+    }
+
+    #[test]
+    fn csv_fields_with_line_breaks_are_quoted() {
+        assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+        assert_eq!(csv_field("a\rb"), "\"a\rb\"");
+        assert_eq!(csv_field("a\r\nb"), "\"a\r\nb\"");
+    }
 }
@@ -0,0 +1,295 @@
+use crate::error::Error;
+use crate::upload::ImageKind;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{DynamicImage, ImageFormat};
+use img_parts::jpeg::{markers, Jpeg};
+use img_parts::png::Png;
+use img_parts::webp::{WebP, CHUNK_XMP};
+use img_parts::{Bytes, ImageEXIF};
+use serde_json::{Map, Value};
+use std::io::Cursor;
+
+const REENCODE_JPEG_QUALITY: u8 = 90;
+const XMP_JPEG_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_PNG_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+const PNG_METADATA_CHUNKS: [[u8; 4]; 5] = [*b"eXIf", *b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"];
+const GIF_KEPT_APPLICATIONS: [&[u8]; 2] = [b"NETSCAPE2.0", b"ANIMEXTS1.0"];
+
+pub struct Stripped {
+    pub content: Vec<u8>,
+    pub kind: ImageKind,
+    /// The EXIF fields and raw XMP packet that were removed, if there were any
+    pub metadata: Option<Value>,
+}
+
+/// Removes EXIF, XMP and other textual metadata from an image.
+///
+/// If the EXIF orientation says the image is rotated or mirrored, the pixels are
+/// transformed and the image is re-encoded so that it still displays the right way up.
+pub fn strip(content: Vec<u8>, kind: ImageKind) -> Result<Stripped, Error> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(&content))
+        .ok();
+
+    let orientation = exif
+        .as_ref()
+        .and_then(|e| e.get_field(exif::Tag::Orientation, exif::In::PRIMARY))
+        .and_then(|f| f.value.get_uint(0))
+        .unwrap_or(1);
+
+    let mut metadata = Map::new();
+    if let Some(exif) = &exif {
+        let fields = exif
+            .fields()
+            .map(|f| {
+                (
+                    format!("{}/{}", f.ifd_num, f.tag),
+                    Value::String(f.display_value().with_unit(exif).to_string()),
+                )
+            })
+            .collect::<Map<_, _>>();
+        metadata.insert("exif".to_string(), Value::Object(fields));
+    }
+
+    if let Some(xmp) = find_xmp(&content, kind) {
+        metadata.insert("xmp".to_string(), Value::String(xmp));
+    }
+
+    let invalid = |e: img_parts::Error| Error::InvalidImage(e.to_string());
+
+    let (content, kind) = if (2..=8).contains(&orientation) {
+        reorient(&content, kind, orientation)?
+    } else {
+        match kind {
+            ImageKind::Jpeg => {
+                let mut jpeg = Jpeg::from_bytes(Bytes::from(content)).map_err(invalid)?;
+                // APP1 holds both EXIF and XMP, APP13 holds IPTC
+                for marker in [markers::APP1, markers::APP13, markers::COM] {
+                    jpeg.remove_segments_by_marker(marker);
+                }
+                (jpeg.encoder().bytes().to_vec(), kind)
+            }
+            ImageKind::Png => {
+                let mut png = Png::from_bytes(Bytes::from(content)).map_err(invalid)?;
+                for chunk in PNG_METADATA_CHUNKS {
+                    png.remove_chunks_by_type(chunk);
+                }
+                (png.encoder().bytes().to_vec(), kind)
+            }
+            ImageKind::Webp => {
+                let mut webp = WebP::from_bytes(Bytes::from(content)).map_err(invalid)?;
+                webp.remove_chunks_by_id(CHUNK_XMP);
+                // Also updates the VP8X flags to match the remaining chunks
+                webp.set_exif(None);
+                (webp.encoder().bytes().to_vec(), kind)
+            }
+            ImageKind::Gif => (strip_gif(&content)?, kind),
+        }
+    };
+
+    Ok(Stripped {
+        content,
+        kind,
+        metadata: (!metadata.is_empty()).then_some(Value::Object(metadata)),
+    })
+}
+
+fn find_xmp(content: &[u8], kind: ImageKind) -> Option<String> {
+    let bytes = Bytes::copy_from_slice(content);
+    let xmp = match kind {
+        ImageKind::Jpeg => Jpeg::from_bytes(bytes)
+            .ok()?
+            .segments_by_marker(markers::APP1)
+            .find_map(|s| s.contents().strip_prefix(XMP_JPEG_PREFIX).map(Vec::from)),
+        ImageKind::Png => Png::from_bytes(bytes)
+            .ok()?
+            .chunks_by_type(*b"iTXt")
+            .find_map(|c| png_xmp_text(c.contents()).map(Vec::from)),
+        ImageKind::Webp => WebP::from_bytes(bytes)
+            .ok()?
+            .chunk_by_id(CHUNK_XMP)
+            .and_then(|c| c.content().data().map(|d| d.to_vec())),
+        _ => None,
+    }?;
+
+    Some(String::from_utf8_lossy(&xmp).to_string())
+}
+
+/// Skips the iTXt header fields preceding the text of an XMP chunk
+fn png_xmp_text(chunk: &[u8]) -> Option<&[u8]> {
+    // Compression flag and method follow the keyword
+    let rest = chunk.strip_prefix(XMP_PNG_KEYWORD)?.get(2..)?;
+    // Then the language tag and the translated keyword
+    let rest = &rest[rest.iter().position(|&b| b == 0)? + 1..];
+    Some(&rest[rest.iter().position(|&b| b == 0)? + 1..])
+}
+
+/// Applies the EXIF orientation to the pixels and encodes the result without any metadata
+fn reorient(
+    content: &[u8],
+    kind: ImageKind,
+    orientation: u32,
+) -> Result<(Vec<u8>, ImageKind), Error> {
+    let format = match kind {
+        ImageKind::Jpeg => ImageFormat::Jpeg,
+        ImageKind::Png => ImageFormat::Png,
+        ImageKind::Webp => ImageFormat::WebP,
+        ImageKind::Gif => ImageFormat::Gif,
+    };
+    let img = image::load_from_memory_with_format(content, format)
+        .map_err(|e| Error::InvalidImage(e.to_string()))?;
+
+    let img = match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    };
+
+    let mut out = Vec::new();
+    let kind = match kind {
+        ImageKind::Jpeg => {
+            JpegEncoder::new_with_quality(&mut out, REENCODE_JPEG_QUALITY)
+                .encode_image(&DynamicImage::ImageRgb8(img.to_rgb8()))
+                .map_err(|e| Error::InvalidImage(e.to_string()))?;
+            ImageKind::Jpeg
+        }
+        ImageKind::Webp => {
+            img.write_with_encoder(WebPEncoder::new_lossless(&mut out))
+                .map_err(|e| Error::InvalidImage(e.to_string()))?;
+            ImageKind::Webp
+        }
+        _ => {
+            img.write_with_encoder(PngEncoder::new(&mut out))
+                .map_err(|e| Error::InvalidImage(e.to_string()))?;
+            ImageKind::Png
+        }
+    };
+
+    Ok((out, kind))
+}
+
+/// Drops comment and application extensions, except for the ones controlling animation
+fn strip_gif(content: &[u8]) -> Result<Vec<u8>, Error> {
+    let malformed = || Error::InvalidImage("Malformed GIF".to_string());
+
+    // Header and logical screen descriptor
+    let flags = *content.get(10).ok_or_else(malformed)?;
+    let mut pos = 13;
+    if flags & 0x80 != 0 {
+        pos += 3 << ((flags & 0x07) + 1);
+    }
+
+    let mut out = content.get(..pos).ok_or_else(malformed)?.to_vec();
+
+    // Returns the position right after the sub-blocks starting at `pos`
+    let skip_sub_blocks = |mut pos: usize| -> Result<usize, Error> {
+        loop {
+            let len = *content.get(pos).ok_or_else(malformed)? as usize;
+            pos += 1 + len;
+            if len == 0 {
+                return Ok(pos);
+            }
+        }
+    };
+
+    loop {
+        let start = pos;
+        match content.get(pos).ok_or_else(malformed)? {
+            0x21 => {
+                let label = *content.get(pos + 1).ok_or_else(malformed)?;
+                let end = skip_sub_blocks(pos + 2)?;
+                let keep = match label {
+                    0xfe => false,
+                    0xff => content
+                        .get(pos + 3..pos + 14)
+                        .is_some_and(|id| GIF_KEPT_APPLICATIONS.contains(&id)),
+                    _ => true,
+                };
+                if keep {
+                    out.extend_from_slice(content.get(start..end).ok_or_else(malformed)?);
+                }
+                pos = end;
+            }
+            0x2c => {
+                let flags = *content.get(pos + 9).ok_or_else(malformed)?;
+                pos += 10;
+                if flags & 0x80 != 0 {
+                    pos += 3 << ((flags & 0x07) + 1);
+                }
+                // LZW minimum code size, then the image data
+                let end = skip_sub_blocks(pos + 1)?;
+                out.extend_from_slice(content.get(start..end).ok_or_else(malformed)?);
+                pos = end;
+            }
+            0x3b => {
+                out.push(0x3b);
+                return Ok(out);
+            }
+            _ => return Err(malformed()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &[u8] = b"GIF89a\x01\0\x01\0\x80\0\0\0\0\0\xff\xff\xff";
+    const GRAPHIC_CONTROL: &[u8] = b"\x21\xf9\x04\0\0\0\0\0";
+    const COMMENT: &[u8] = b"\x21\xfe\x05hello\0";
+    const ANIMATION: &[u8] = b"\x21\xff\x0bNETSCAPE2.0\x03\x01\0\0\0";
+    const XMP: &[u8] = b"\x21\xff\x0bXMP DataXMP\x04<x/>\x02ab\0";
+    /// A single pixel with a local color table
+    const IMAGE: &[u8] = b"\x2c\0\0\0\0\x01\0\x01\0\x80\0\0\0\xff\xff\xff\x02\x02\x44\x01\0";
+    const TRAILER: &[u8] = b"\x3b";
+
+    #[test]
+    fn gif_comments_and_application_data_are_removed() {
+        let gif = [
+            HEADER,
+            GRAPHIC_CONTROL,
+            COMMENT,
+            ANIMATION,
+            XMP,
+            IMAGE,
+            COMMENT,
+            TRAILER,
+        ]
+        .concat();
+        let stripped = strip_gif(&gif).unwrap();
+
+        assert_eq!(
+            stripped,
+            [HEADER, GRAPHIC_CONTROL, ANIMATION, IMAGE, TRAILER].concat()
+        );
+        assert!(image::load_from_memory_with_format(&stripped, ImageFormat::Gif).is_ok());
+    }
+
+    #[test]
+    fn gif_without_metadata_is_unchanged() {
+        let gif = [HEADER, GRAPHIC_CONTROL, IMAGE, TRAILER].concat();
+        assert_eq!(strip_gif(&gif).unwrap(), gif);
+    }
+
+    #[test]
+    fn truncated_gif_is_rejected() {
+        let gif = [HEADER, COMMENT, ANIMATION, IMAGE, TRAILER].concat();
+        for len in 0..gif.len() {
+            assert!(strip_gif(&gif[..len]).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn unknown_gif_blocks_are_rejected() {
+        let gif = [HEADER, b"\x00", IMAGE, TRAILER].concat();
+        assert!(strip_gif(&gif).is_err());
+    }
+}
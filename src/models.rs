@@ -1,5 +1,9 @@
-use chrono::NaiveDate;
+use crate::database::Database;
+use crate::{thumbnail, upload};
+use chrono::{Datelike, NaiveDate};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
+use rand::SeedableRng;
 use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
 use std::sync::LazyLock;
@@ -73,19 +77,173 @@ impl BlogContext {
     }
 }
 
-#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct Image {
+    pub id: i32,
     pub path: String,
     pub name: String,
+    pub thumb_path: String,
+    pub original_name: Option<String>,
+    pub caption: Option<String>,
+    pub alt: Option<String>,
+    pub album: Option<String>,
+    pub uploaded: chrono::NaiveDateTime,
+    pub width: i32,
+    pub height: i32,
+    pub hash: String,
+    pub placeholder: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// ISO 3166-1 alpha-2 code of the country the screenshot was taken in
+    pub country: Option<String>,
+    /// The round of the GeoGuessr game the screenshot is from
+    pub round: Option<i32>,
+    /// The token of the GeoGuessr game the screenshot is from
+    pub game: Option<String>,
 }
 
-impl Image {
-    pub fn new(path: &str) -> Self {
+impl From<ImageRow> for Image {
+    fn from(row: ImageRow) -> Self {
+        let name = row.path.rsplit('/').next().unwrap_or_default().to_string();
+
+        // Fall back to the original until a thumbnail has been generated
+        let thumb_path = thumbnail::thumbnail_path(&name);
+        let thumb_path = if std::path::Path::new(&format!(".{}", &thumb_path)).exists() {
+            thumb_path
+        } else {
+            row.path.clone()
+        };
+
         Self {
-            name: path.rsplit('/').next().unwrap_or_default().to_string(),
-            path: path[1..].to_string(), // Strip the redundant "." from the start
+            id: row.id,
+            path: row.path,
+            name,
+            thumb_path,
+            original_name: row.original_name,
+            caption: row.caption,
+            alt: row.alt,
+            album: row.album,
+            uploaded: row.uploaded,
+            width: row.width,
+            height: row.height,
+            hash: row.hash,
+            placeholder: row.placeholder,
+            latitude: row.latitude,
+            longitude: row.longitude,
+            country: row.country,
+            round: row.round,
+            game: row.game,
+        }
+    }
+}
+
+impl Image {
+    pub fn create_thumbnail(&mut self) -> image::ImageResult<()> {
+        let thumb_path = thumbnail::thumbnail_path(&self.name);
+        if thumb_path != self.thumb_path {
+            thumbnail::generate(
+                std::path::Path::new(&format!(".{}", &self.path)),
+                &self.name,
+            )?;
+            self.thumb_path = thumb_path;
+        }
+        Ok(())
+    }
+
+    /// Returns whether a new placeholder was created, so that it can be saved
+    pub fn create_placeholder(&mut self) -> image::ImageResult<bool> {
+        if self.placeholder.is_some() {
+            return Ok(false);
+        }
+
+        // The thumbnail is a lot cheaper to decode than the original
+        self.placeholder = Some(thumbnail::placeholder(std::path::Path::new(&format!(
+            ".{}",
+            &self.thumb_path
+        )))?);
+        Ok(true)
+    }
+
+    /// Creates any missing thumbnail or placeholder, returning the changes to save
+    pub fn prepare(&mut self) -> ImageChanges {
+        if let Err(e) = self.create_thumbnail() {
+            log::warn!("Failed to create a thumbnail for {}: {}", self.path, e);
+        }
+
+        match self.create_placeholder() {
+            Ok(true) => ImageChanges {
+                placeholder: Some(self.placeholder.clone()),
+                ..Default::default()
+            },
+            Ok(false) => ImageChanges::default(),
+            Err(e) => {
+                log::warn!("Failed to create a placeholder for {}: {}", self.path, e);
+                ImageChanges::default()
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GalleryOrder {
+    #[default]
+    Shuffle,
+    Newest,
+    Oldest,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GalleryQuery {
+    pub page: Option<usize>,
+    #[serde(default)]
+    pub order: GalleryOrder,
+    pub seed: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Album {
+    pub name: String,
+    pub count: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Country {
+    pub code: String,
+    pub count: usize,
+}
+
+/// The part of an image needed to place it on the map
+#[derive(Serialize, Debug, Clone)]
+pub struct MapMarker {
+    pub name: String,
+    pub path: String,
+    pub thumb_path: String,
+    pub caption: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub country: Option<String>,
+    pub round: Option<i32>,
+    pub game: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MapQuery {
+    pub country: Option<String>,
+}
+
+/// Counts how many times each value occurs, sorted by the value
+fn tally<'a>(values: impl Iterator<Item = &'a String>) -> Vec<(String, usize)> {
+    let mut counts = Vec::<(String, usize)>::new();
+    for value in values {
+        match counts.iter_mut().find(|(v, _)| v == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value.clone(), 1)),
         }
     }
+
+    counts.sort_by(|a, b| a.0.cmp(&b.0));
+    counts
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -95,25 +253,159 @@ pub struct ImageGallery {
 }
 
 impl ImageGallery {
-    pub fn new(path: &str) -> Self {
-        let images = std::fs::read_dir(std::path::Path::new(path))
-            .unwrap()
-            .filter_map(|file| Some(Image::new(file.ok()?.path().to_str()?)))
+    pub async fn new(path: &str, db: &Database) -> Result<Self, crate::error::Error> {
+        let mut images = db
+            .images()
+            .await?
+            .into_iter()
+            .map(Image::from)
             .collect::<Vec<_>>();
 
-        Self {
+        for img in images.iter_mut() {
+            let changes = img.prepare();
+            if changes.placeholder.is_some() {
+                db.update_image(img.id, changes).await?;
+            }
+        }
+
+        Ok(Self {
             path: path.to_string(),
             images,
+        })
+    }
+
+    /// Adds the files in the gallery directory that are not in the database yet.
+    /// Returns the number of imported images.
+    pub async fn import(&mut self, db: &Database) -> Result<usize, crate::error::Error> {
+        let mut imported = 0;
+
+        for file in std::fs::read_dir(std::path::Path::new(&self.path))? {
+            let file = file?;
+            // Hidden files are not gallery images
+            if !file.file_type()?.is_file() || file.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            let path = format!(
+                "{}{}",
+                &self.path[1..], // Strip the redundant "." from the start
+                file.file_name().to_string_lossy()
+            );
+            if self.images.iter().any(|i| i.path == path) {
+                continue;
+            }
+
+            let content = std::fs::read(file.path())?;
+            let size = match imagesize::blob_size(&content) {
+                Ok(size) => size,
+                Err(e) => {
+                    log::warn!("Skipping {}: {}", path, e);
+                    continue;
+                }
+            };
+
+            let row = db
+                .new_image(NewImage {
+                    path,
+                    original_name: None,
+                    caption: None,
+                    album: None,
+                    location: Location::default(),
+                    uploaded: chrono::DateTime::<chrono::Local>::from(file.metadata()?.modified()?)
+                        .naive_local(),
+                    width: size.width as i32,
+                    height: size.height as i32,
+                    hash: upload::content_hash(&content),
+                })
+                .await?;
+
+            let mut img = Image::from(row);
+            let changes = img.prepare();
+            if changes.placeholder.is_some() {
+                db.update_image(img.id, changes).await?;
+            }
+            self.add_image(img);
+            imported += 1;
+        }
+
+        Ok(imported)
+    }
+
+    /// The names of all albums along with the number of images in them
+    pub fn albums(&self) -> Vec<Album> {
+        tally(self.images.iter().filter_map(|i| i.album.as_ref()))
+            .into_iter()
+            .map(|(name, count)| Album { name, count })
+            .collect()
+    }
+
+    /// The countries of the images that have a location, along with the number of images
+    pub fn countries(&self) -> Vec<Country> {
+        tally(
+            self.images
+                .iter()
+                .filter(|i| i.latitude.is_some())
+                .filter_map(|i| i.country.as_ref()),
+        )
+        .into_iter()
+        .map(|(code, count)| Country { code, count })
+        .collect()
+    }
+
+    /// The images that have a location, optionally only the ones from the given country
+    pub fn markers(&self, country: Option<&str>) -> Vec<MapMarker> {
+        self.images
+            .iter()
+            .filter(|i| country.is_none() || i.country.as_deref() == country)
+            .filter_map(|i| {
+                Some(MapMarker {
+                    name: i.name.clone(),
+                    path: i.path.clone(),
+                    thumb_path: i.thumb_path.clone(),
+                    caption: i.caption.clone(),
+                    latitude: i.latitude?,
+                    longitude: i.longitude?,
+                    country: i.country.clone(),
+                    round: i.round,
+                    game: i.game.clone(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Image> {
+        self.images.iter().find(|i| i.name == name)
+    }
+
+    pub fn remove_image(&mut self, name: &str) {
+        self.images.retain(|i| i.name != name)
+    }
+
+    pub fn replace_image(&mut self, name: &str, img: Image) {
+        if let Some(i) = self.images.iter_mut().find(|i| i.name == name) {
+            *i = img;
         }
     }
 
     pub fn add_image(&mut self, img: Image) {
-        self.images.push(img)
+        if !self.images.iter().any(|i| i.name == img.name) {
+            self.images.push(img)
+        }
     }
 
-    pub fn shuffle(&mut self) {
-        let mut rng = rand::thread_rng();
-        self.images.shuffle(&mut rng);
+    /// A copy of the images in the given order. Shuffling with the same seed
+    /// always yields the same order, so it can be kept stable across pages.
+    pub fn ordered(&self, order: GalleryOrder, seed: u64) -> Vec<Image> {
+        let mut images = self.images.clone();
+        images.sort_by_key(|i| i.id);
+
+        match order {
+            GalleryOrder::Shuffle => images.shuffle(&mut StdRng::seed_from_u64(seed)),
+            GalleryOrder::Newest => images.sort_by_key(|i| std::cmp::Reverse(i.uploaded)),
+            GalleryOrder::Oldest => images.sort_by_key(|i| i.uploaded),
+        }
+
+        images
     }
 }
 
@@ -196,12 +488,227 @@ impl From<Option<Activity>> for IndexActivity {
     }
 }
 
-#[derive(Queryable, Clone, Serialize)]
+#[derive(QueryableByName, Clone, Serialize)]
 pub struct Visits {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub path: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub visit_count: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsInterval {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl StatsInterval {
+    /// The field name understood by `date_trunc`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Day => "day",
+            Self::Week => "week",
+            Self::Month => "month",
+        }
+    }
+
+    pub fn approx_days(&self) -> i64 {
+        match self {
+            Self::Day => 1,
+            Self::Week => 7,
+            Self::Month => 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StatsQuery {
+    /// The first day to include, 30 days ago by default
+    pub from: Option<NaiveDate>,
+    /// The last day to include, today by default
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    pub interval: StatsInterval,
+    /// Only count the visits to this path
+    pub path: Option<String>,
+    /// Also count the visits by bots, which are left out by default
+    #[serde(default)]
+    pub bots: bool,
+}
+
+const STATS_DEFAULT_DAYS: i64 = 30;
+const STATS_MAX_PERIODS: i64 = 1000;
+
+fn out_of_range() -> crate::error::Error {
+    crate::error::Error::BadRequest("The range is outside of the supported dates".to_string())
+}
+
+impl StatsQuery {
+    /// The first and last day to include
+    pub fn range(&self) -> Result<(NaiveDate, NaiveDate), crate::error::Error> {
+        let to = self.to.unwrap_or_else(|| chrono::Local::now().date_naive());
+        let from = match self.from {
+            Some(from) => from,
+            None => to
+                .checked_sub_signed(chrono::Duration::days(STATS_DEFAULT_DAYS - 1))
+                .ok_or_else(out_of_range)?,
+        };
+
+        // Keeps the dates within what both chrono and Postgres can count with
+        if !(1..=9999).contains(&from.year()) || !(1..=9999).contains(&to.year()) {
+            return Err(out_of_range());
+        }
+        if from > to {
+            return Err(crate::error::Error::BadRequest(
+                "The start of the range is after its end".to_string(),
+            ));
+        }
+        if (to - from).num_days() / self.interval.approx_days() > STATS_MAX_PERIODS {
+            return Err(crate::error::Error::BadRequest(format!(
+                "The range is too long to be shown by {}",
+                self.interval.as_str()
+            )));
+        }
+
+        Ok((from, to))
+    }
+
+    /// The range as timestamps, the end being exclusive
+    pub fn timestamps(
+        &self,
+    ) -> Result<(chrono::NaiveDateTime, chrono::NaiveDateTime), crate::error::Error> {
+        let (from, to) = self.range()?;
+        let end = to
+            .checked_add_signed(chrono::Duration::days(1))
+            .ok_or_else(out_of_range)?;
+        Ok((
+            from.and_time(chrono::NaiveTime::MIN),
+            end.and_time(chrono::NaiveTime::MIN),
+        ))
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref().filter(|p| !p.is_empty())
+    }
+}
+
+/// Page views and unique visitors during a day, week or month
+#[derive(QueryableByName, Clone, Serialize)]
+pub struct PeriodVisits {
+    #[diesel(sql_type = diesel::sql_types::Date)]
+    pub period: NaiveDate,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub views: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub visitors: i64,
+}
+
+/// Page views and unique visitors of a path during a day, week or month
+#[derive(QueryableByName, Clone, Serialize)]
+pub struct PeriodPathVisits {
+    #[diesel(sql_type = diesel::sql_types::Date)]
+    pub period: NaiveDate,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub path: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub views: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub visitors: i64,
+}
+
+#[derive(QueryableByName, Clone, Serialize)]
+pub struct PathVisits {
+    #[diesel(sql_type = diesel::sql_types::Text)]
     pub path: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub views: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub visitors: i64,
+    /// The average response time in milliseconds, unknown for visits recorded without one
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Integer>)]
+    pub latency: Option<i32>,
+}
+
+#[derive(QueryableByName, Clone, Serialize)]
+pub struct ReferrerVisits {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub referrer: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub views: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub visitors: i64,
+}
+
+#[derive(QueryableByName, Clone, Serialize)]
+pub struct CountryVisits {
+    /// The ISO 3166-1 code of the country, unknown for visits recorded without one
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Text>)]
+    pub country: Option<String>,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub views: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub visitors: i64,
+}
+
+#[derive(QueryableByName, Clone)]
+pub struct UserAgentVisits {
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Text>)]
+    pub user_agent: Option<String>,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub views: i64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BrowserVisits {
+    pub browser: &'static str,
+    pub views: i64,
+}
+
+impl BrowserVisits {
+    /// Adds up the views of the user agents of each browser, most viewed first
+    pub fn from_user_agents(user_agents: Vec<UserAgentVisits>) -> Vec<Self> {
+        let mut browsers = Vec::<Self>::new();
+        for ua in user_agents {
+            let browser = crate::useragent::browser(ua.user_agent.as_deref());
+            match browsers.iter_mut().find(|b| b.browser == browser) {
+                Some(b) => b.views += ua.views,
+                None => browsers.push(Self {
+                    browser,
+                    views: ua.views,
+                }),
+            }
+        }
+
+        browsers.sort_by_key(|b| std::cmp::Reverse(b.views));
+        browsers
+    }
+}
+
+#[derive(QueryableByName, Clone, Serialize)]
+pub struct VisitTotals {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub views: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub visitors: i64,
+}
+
+#[derive(QueryableByName, Clone, Serialize)]
+pub struct DailyVisits {
+    #[diesel(sql_type = diesel::sql_types::Date)]
+    pub day: NaiveDate,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
     pub visit_count: i64,
 }
 
+#[derive(Queryable, Clone, Serialize)]
+pub struct RecentVisit {
+    pub visitor: String,
+    pub instance: chrono::NaiveDateTime,
+}
+
 use crate::schema::visits;
 #[derive(Insertable)]
 #[diesel(table_name = visits)]
@@ -210,3 +717,218 @@ pub struct Visit {
     pub path: String,
     pub instance: chrono::NaiveDateTime,
 }
+
+use crate::schema::image_metadata;
+#[derive(Insertable)]
+#[diesel(table_name = image_metadata)]
+pub struct ImageMetadata {
+    pub name: String,
+    pub metadata: serde_json::Value,
+    pub created: chrono::NaiveDateTime,
+}
+
+use crate::schema::deployments;
+#[derive(Queryable, Serialize, Debug, Clone)]
+pub struct Deployment {
+    pub id: i32,
+    pub started: chrono::NaiveDateTime,
+    /// Either "restart" for a new build or "reload" for a template and blog reload
+    pub kind: String,
+    /// The commit that was checked out
+    pub revision: Option<String>,
+    pub output: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = deployments)]
+pub struct NewDeployment {
+    pub started: chrono::NaiveDateTime,
+    pub kind: String,
+    pub revision: Option<String>,
+    pub output: String,
+}
+
+use crate::schema::api_tokens;
+/// A stored API token, without the hash that is only needed to look it up
+#[derive(Queryable, Selectable, Serialize, Debug, Clone)]
+#[diesel(table_name = api_tokens)]
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created: chrono::NaiveDateTime,
+    pub expires: Option<chrono::NaiveDateTime>,
+    pub last_used: Option<chrono::NaiveDateTime>,
+    pub revoked: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = api_tokens)]
+pub struct NewApiToken {
+    pub name: String,
+    pub hash: String,
+    pub scopes: Vec<String>,
+    pub created: chrono::NaiveDateTime,
+    pub expires: Option<chrono::NaiveDateTime>,
+}
+
+use crate::schema::images;
+#[derive(Queryable, Clone)]
+pub struct ImageRow {
+    pub id: i32,
+    pub path: String,
+    pub original_name: Option<String>,
+    pub caption: Option<String>,
+    pub uploaded: chrono::NaiveDateTime,
+    pub width: i32,
+    pub height: i32,
+    pub hash: String,
+    pub alt: Option<String>,
+    pub album: Option<String>,
+    pub placeholder: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub country: Option<String>,
+    pub round: Option<i32>,
+    pub game: Option<String>,
+}
+
+/// Where and in which GeoGuessr game a screenshot was taken
+#[derive(Insertable, Deserialize, Debug, Clone, Default)]
+#[diesel(table_name = images)]
+pub struct Location {
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub country: Option<String>,
+    pub round: Option<i32>,
+    pub game: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = images)]
+pub struct NewImage {
+    pub path: String,
+    pub original_name: Option<String>,
+    pub caption: Option<String>,
+    pub album: Option<String>,
+    #[diesel(embed)]
+    pub location: Location,
+    pub uploaded: chrono::NaiveDateTime,
+    pub width: i32,
+    pub height: i32,
+    pub hash: String,
+}
+
+#[derive(AsChangeset, Default)]
+#[diesel(table_name = images)]
+pub struct ImageChanges {
+    pub path: Option<String>,
+    pub caption: Option<Option<String>>,
+    pub alt: Option<Option<String>>,
+    pub album: Option<Option<String>>,
+    pub placeholder: Option<Option<String>>,
+    pub latitude: Option<Option<f64>>,
+    pub longitude: Option<Option<f64>>,
+    pub country: Option<Option<String>>,
+    pub round: Option<Option<i32>>,
+    pub game: Option<Option<String>>,
+}
+
+impl ImageChanges {
+    /// Diesel refuses to run an update without any columns to set
+    pub fn is_empty(&self) -> bool {
+        self.path.is_none()
+            && self.caption.is_none()
+            && self.alt.is_none()
+            && self.album.is_none()
+            && self.placeholder.is_none()
+            && self.latitude.is_none()
+            && self.longitude.is_none()
+            && self.country.is_none()
+            && self.round.is_none()
+            && self.game.is_none()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImagePatch {
+    /// An empty string removes the caption
+    pub caption: Option<String>,
+    /// An empty string removes the alt text
+    pub alt: Option<String>,
+    /// The new file name, the extension is kept as is
+    pub name: Option<String>,
+    /// An empty string removes the image from its album
+    pub album: Option<String>,
+    /// Null removes the coordinates, which have to be changed together
+    #[serde(default, deserialize_with = "nullable")]
+    pub latitude: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub longitude: Option<Option<f64>>,
+    /// An empty string removes the country
+    pub country: Option<String>,
+    /// Null removes the round
+    #[serde(default, deserialize_with = "nullable")]
+    pub round: Option<Option<i32>>,
+    /// An empty string removes the game
+    pub game: Option<String>,
+}
+
+/// Tells a field that is explicitly set to null apart from one that is missing
+fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: serde::Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    <Option<T> as serde::Deserialize>::deserialize(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(from: Option<NaiveDate>, to: Option<NaiveDate>) -> StatsQuery {
+        StatsQuery {
+            from,
+            to,
+            interval: StatsInterval::Day,
+            path: None,
+            bots: false,
+        }
+    }
+
+    #[test]
+    fn stats_range_defaults_to_the_last_30_days() {
+        let to = NaiveDate::from_ymd_opt(2023, 8, 31).unwrap();
+        let (from, _) = query(None, Some(to)).range().unwrap();
+        assert_eq!(from, NaiveDate::from_ymd_opt(2023, 8, 2).unwrap());
+    }
+
+    #[test]
+    fn stats_range_rejects_dates_out_of_bounds() {
+        for (from, to) in [
+            (None, Some(NaiveDate::MIN)),
+            (Some(NaiveDate::MAX), Some(NaiveDate::MAX)),
+            (
+                NaiveDate::from_ymd_opt(0, 12, 31),
+                NaiveDate::from_ymd_opt(1, 1, 1),
+            ),
+            (
+                NaiveDate::from_ymd_opt(9999, 12, 31),
+                NaiveDate::from_ymd_opt(10000, 1, 1),
+            ),
+        ] {
+            assert!(matches!(
+                query(from, to).timestamps(),
+                Err(crate::error::Error::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn stats_range_ends_after_the_last_day() {
+        let day = NaiveDate::from_ymd_opt(9999, 12, 31).unwrap();
+        let (from, to) = query(Some(day), Some(day)).timestamps().unwrap();
+        assert_eq!(to - from, chrono::Duration::days(1));
+    }
+}
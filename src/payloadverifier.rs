@@ -67,7 +67,7 @@ where
             mac.update(&body);
 
             // Sig string is sha256=deadbeef
-            let real_signature = signature.to_str().unwrap()[7..].as_bytes();
+            let real_signature = &signature.to_str().unwrap().as_bytes()[7..];
 
             if mac
                 .verify_slice(&Vec::from_hex(real_signature).unwrap())
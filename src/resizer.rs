@@ -0,0 +1,243 @@
+use crate::error::Error;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::ImageFormat;
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Widths and heights that may be requested, so that the cache cannot be filled with every size
+pub const ALLOWED_SIZES: [u32; 7] = [160, 320, 480, 640, 960, 1280, 1920];
+const DEFAULT_CACHE_DIR: &str = "./cache/img/";
+const DEFAULT_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl OutputFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResizeQuery {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub fmt: Option<OutputFormat>,
+}
+
+pub struct Resized {
+    pub path: PathBuf,
+    pub etag: String,
+    pub format: OutputFormat,
+}
+
+/// An on-disk cache of resized images, evicting the least recently used ones when full
+pub struct ImageCache {
+    root: PathBuf,
+    dir: PathBuf,
+    max_size: u64,
+    size: Mutex<u64>,
+}
+
+impl ImageCache {
+    pub fn new(root: &str) -> Self {
+        let dir = PathBuf::from(
+            std::env::var("IMAGE_CACHE_DIR").unwrap_or_else(|_| DEFAULT_CACHE_DIR.to_string()),
+        );
+        let max_size = std::env::var("IMAGE_CACHE_MAX_SIZE")
+            .ok()
+            .map(|s| s.parse().expect("IMAGE_CACHE_MAX_SIZE is not a number"))
+            .unwrap_or(DEFAULT_CACHE_SIZE);
+
+        std::fs::create_dir_all(&dir).expect("Failed to create the image cache directory");
+        let size = cache_entries(&dir).iter().map(|(_, len, _)| len).sum();
+
+        Self {
+            root: PathBuf::from(root)
+                .canonicalize()
+                .expect("Failed to resolve the static directory"),
+            dir,
+            max_size,
+            size: Mutex::new(size),
+        }
+    }
+
+    /// Returns the cached file for the request, resizing the source image if necessary
+    pub fn get(&self, path: &str, query: &ResizeQuery) -> Result<Resized, Error> {
+        for size in [query.w, query.h].into_iter().flatten() {
+            if !ALLOWED_SIZES.contains(&size) {
+                return Err(Error::BadRequest(format!(
+                    "Unsupported size {}, expected one of {:?}",
+                    size, ALLOWED_SIZES
+                )));
+            }
+        }
+
+        let source = self.resolve(path)?;
+        let source_format = ImageFormat::from_path(&source)
+            .map_err(|_| Error::NotFound(format!("{} is not an image", path)))?;
+        let format = query.fmt.unwrap_or(match source_format {
+            ImageFormat::Jpeg => OutputFormat::Jpeg,
+            ImageFormat::WebP => OutputFormat::Webp,
+            _ => OutputFormat::Png,
+        });
+
+        // The modification time is part of the key so that replaced images are not served stale
+        let modified = source
+            .metadata()?
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let key = hex::encode(Sha256::digest(format!(
+            "{}:{}:{:?}:{:?}:{}",
+            path,
+            modified.as_nanos(),
+            query.w,
+            query.h,
+            format.extension()
+        )));
+        let cached = self.dir.join(format!("{}.{}", key, format.extension()));
+
+        if cached.exists() {
+            // Marks the entry as recently used
+            std::fs::File::options()
+                .write(true)
+                .open(&cached)?
+                .set_modified(SystemTime::now())?;
+        } else {
+            let len = resize(&source, &cached, query.w, query.h, format)?;
+            self.make_room(len);
+        }
+
+        Ok(Resized {
+            path: cached,
+            etag: format!("\"{}\"", key),
+            format,
+        })
+    }
+
+    /// Maps the requested path to a file inside the root directory
+    fn resolve(&self, path: &str) -> Result<PathBuf, Error> {
+        let not_found = || Error::NotFound(format!("No such image {}", path));
+
+        if Path::new(path)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(not_found());
+        }
+
+        let source = self
+            .root
+            .join(path)
+            .canonicalize()
+            .map_err(|_| not_found())?;
+        if !source.starts_with(&self.root) || !source.is_file() {
+            return Err(not_found());
+        }
+
+        Ok(source)
+    }
+
+    fn make_room(&self, added: u64) {
+        let mut size = self.size.lock().unwrap();
+        *size += added;
+        if *size <= self.max_size {
+            return;
+        }
+
+        let mut entries = cache_entries(&self.dir);
+        entries.sort_by_key(|(_, _, used)| *used);
+        *size = entries.iter().map(|(_, len, _)| len).sum();
+
+        for (path, len, _) in entries {
+            if *size <= self.max_size {
+                break;
+            }
+            if std::fs::remove_file(&path).is_ok() {
+                *size -= len;
+            }
+        }
+    }
+}
+
+/// The files in the cache along with their sizes and last use times
+fn cache_entries(dir: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| {
+                    let e = e.ok()?;
+                    let metadata = e.metadata().ok()?;
+                    Some((e.path(), metadata.len(), metadata.modified().ok()?))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Scales the image to fit within the given dimensions without upscaling and
+/// writes it to `out`. Returns the size of the written file.
+fn resize(
+    source: &Path,
+    out: &Path,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: OutputFormat,
+) -> Result<u64, Error> {
+    let invalid = |e: image::ImageError| Error::InvalidImage(e.to_string());
+
+    let img = image::open(source).map_err(invalid)?;
+    let width = width.unwrap_or(u32::MAX).min(img.width());
+    let height = height.unwrap_or(u32::MAX).min(img.height());
+    let img = if width < img.width() || height < img.height() {
+        img.resize(width, height, FilterType::Lanczos3)
+    } else {
+        img
+    };
+
+    let mut content = Vec::new();
+    match format {
+        OutputFormat::Jpeg => JpegEncoder::new_with_quality(&mut content, JPEG_QUALITY)
+            .encode_image(&img.to_rgb8())
+            .map_err(invalid)?,
+        OutputFormat::Png => img
+            .write_with_encoder(PngEncoder::new(&mut content))
+            .map_err(invalid)?,
+        OutputFormat::Webp => img
+            .write_with_encoder(WebPEncoder::new_lossless(&mut content))
+            .map_err(invalid)?,
+    }
+
+    // Written under a temporary name so that a half written file is never served
+    let tmp = out.with_extension(format!("{}.tmp", rand::random::<u32>()));
+    std::fs::write(&tmp, &content)?;
+    std::fs::rename(&tmp, out)?;
+
+    Ok(content.len() as u64)
+}
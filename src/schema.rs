@@ -1,8 +1,110 @@
+diesel::table! {
+    api_tokens (id) {
+        id -> Int4,
+        name -> Text,
+        hash -> Text,
+        scopes -> Array<Text>,
+        created -> Timestamp,
+        expires -> Nullable<Timestamp>,
+        last_used -> Nullable<Timestamp>,
+        revoked -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    daily_totals (day, is_bot, country) {
+        day -> Date,
+        is_bot -> Bool,
+        views -> Int8,
+        visitors -> Int8,
+        country -> Text,
+    }
+}
+
+diesel::table! {
+    daily_visits (day, path, is_bot, country) {
+        day -> Date,
+        path -> Text,
+        is_bot -> Bool,
+        views -> Int8,
+        visitors -> Int8,
+        latency_ms_sum -> Int8,
+        latency_count -> Int8,
+        country -> Text,
+    }
+}
+
+diesel::table! {
+    deployments (id) {
+        id -> Int4,
+        started -> Timestamp,
+        kind -> Text,
+        revision -> Nullable<Text>,
+        output -> Text,
+    }
+}
+
+diesel::table! {
+    image_metadata (name) {
+        name -> Text,
+        metadata -> Jsonb,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    images (id) {
+        id -> Int4,
+        path -> Text,
+        original_name -> Nullable<Text>,
+        caption -> Nullable<Text>,
+        uploaded -> Timestamp,
+        width -> Int4,
+        height -> Int4,
+        hash -> Text,
+        alt -> Nullable<Text>,
+        album -> Nullable<Text>,
+        placeholder -> Nullable<Text>,
+        latitude -> Nullable<Float8>,
+        longitude -> Nullable<Float8>,
+        country -> Nullable<Text>,
+        round -> Nullable<Int4>,
+        game -> Nullable<Text>,
+    }
+}
+
 diesel::table! {
     visits (id) {
         id -> Int4,
         visitor -> Text,
         path -> Text,
         instance -> Timestamp,
+        referrer -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        status -> Nullable<Int4>,
+        latency_ms -> Nullable<Int4>,
+        is_bot -> Bool,
+        utm_source -> Nullable<Text>,
+        utm_medium -> Nullable<Text>,
+        utm_campaign -> Nullable<Text>,
+        country -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    visitor_salts (day) {
+        day -> Date,
+        salt -> Text,
     }
 }
+
+diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    daily_totals,
+    daily_visits,
+    deployments,
+    image_metadata,
+    images,
+    visitor_salts,
+    visits,
+);
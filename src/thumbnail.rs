@@ -0,0 +1,64 @@
+use base64::Engine;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::ImageResult;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+pub const THUMBNAIL_DIR: &str = "./static/thumbnails/";
+pub const THUMBNAIL_WIDTH: u32 = 400;
+const THUMBNAIL_QUALITY: u8 = 80;
+const PLACEHOLDER_SIZE: u32 = 16;
+const PLACEHOLDER_QUALITY: u8 = 50;
+
+/// The path under which the thumbnail of the image called `name` is served
+pub fn thumbnail_path(name: &str) -> String {
+    format!("{}{}.jpg", &THUMBNAIL_DIR[1..], name)
+}
+
+/// Scales `source` down to `THUMBNAIL_WIDTH` and stores it as a jpeg in `THUMBNAIL_DIR`.
+/// Images narrower than that are only re-encoded.
+pub fn generate(source: &Path, name: &str) -> ImageResult<()> {
+    std::fs::create_dir_all(THUMBNAIL_DIR)?;
+
+    let img = image::open(source)?;
+    let img = if img.width() > THUMBNAIL_WIDTH {
+        img.resize(THUMBNAIL_WIDTH, u32::MAX, FilterType::Triangle)
+    } else {
+        img
+    };
+
+    let out = BufWriter::new(File::create(format!(".{}", thumbnail_path(name)))?);
+    JpegEncoder::new_with_quality(out, THUMBNAIL_QUALITY).encode_image(&img.to_rgb8())
+}
+
+/// A tiny blurry version of `source` as a data URI, shown while the real image loads
+pub fn placeholder(source: &Path) -> ImageResult<String> {
+    let img = image::open(source)?.thumbnail(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE);
+
+    let mut out = Vec::new();
+    JpegEncoder::new_with_quality(&mut out, PLACEHOLDER_QUALITY).encode_image(&img.to_rgb8())?;
+
+    Ok(format!(
+        "data:image/jpeg;base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(out)
+    ))
+}
+
+pub fn remove(name: &str) -> std::io::Result<()> {
+    match std::fs::remove_file(format!(".{}", thumbnail_path(name))) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+pub fn rename(from: &str, to: &str) -> std::io::Result<()> {
+    match std::fs::rename(
+        format!(".{}", thumbnail_path(from)),
+        format!(".{}", thumbnail_path(to)),
+    ) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,447 @@
+use crate::error::Error;
+use crate::models::Location;
+
+use actix_multipart::Field;
+use futures_util::stream::StreamExt as _;
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+const DEFAULT_MAX_UPLOAD_SIZE: usize = 20 * 1024 * 1024;
+const DEFAULT_FETCH_TIMEOUT: u64 = 10;
+pub const MAX_FILES_PER_UPLOAD: usize = 32;
+const MAX_DIMENSION: usize = 16384;
+const MAX_PIXELS: usize = 100_000_000;
+
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    pub max_size: usize,
+    /// How long fetching an image from a URL may take
+    pub fetch_timeout: Duration,
+    /// Whether the metadata stripped from uploads is kept in the database
+    pub keep_metadata: bool,
+}
+
+impl UploadConfig {
+    pub fn from_env() -> Self {
+        let max_size = std::env::var("GALLERY_MAX_UPLOAD_SIZE")
+            .ok()
+            .map(|s| s.parse().expect("GALLERY_MAX_UPLOAD_SIZE is not a number"))
+            .unwrap_or(DEFAULT_MAX_UPLOAD_SIZE);
+
+        let fetch_timeout = std::env::var("GALLERY_FETCH_TIMEOUT")
+            .ok()
+            .map(|s| s.parse().expect("GALLERY_FETCH_TIMEOUT is not a number"))
+            .unwrap_or(DEFAULT_FETCH_TIMEOUT);
+
+        let keep_metadata = std::env::var("GALLERY_KEEP_METADATA")
+            .map(|s| s == "1" || s == "true")
+            .unwrap_or(false);
+
+        Self {
+            max_size,
+            fetch_timeout: Duration::from_secs(fetch_timeout),
+            keep_metadata,
+        }
+    }
+}
+
+/// The accepted image formats. AVIF is left out, since the `image` crate is built without
+/// a decoder for it and the thumbnails could not be generated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    Png,
+    Jpeg,
+    Webp,
+    Gif,
+}
+
+impl ImageKind {
+    /// Detects the format from the magic bytes at the start of the file
+    pub fn sniff(content: &[u8]) -> Option<Self> {
+        match content {
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Some(Self::Png),
+            [0xff, 0xd8, 0xff, ..] => Some(Self::Jpeg),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(Self::Webp),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(Self::Gif),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+            Self::Gif => "gif",
+        }
+    }
+}
+
+/// The fields of an upload that apply to every image in it
+#[derive(Clone, Debug, Default)]
+pub struct UploadFields {
+    pub album: Option<String>,
+    pub location: Location,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UploadedImage {
+    pub url: String,
+    pub name: String,
+    pub original_name: Option<String>,
+    pub duplicate: bool,
+}
+
+/// The outcome of storing one of the files or URLs of an upload
+#[derive(Serialize, Debug)]
+pub struct UploadResult {
+    /// The client supplied filename or the URL the image was fetched from
+    pub source: Option<String>,
+    #[serde(flatten)]
+    pub image: Option<UploadedImage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Downloads an image, giving up if it takes longer than `timeout` or grows past `max_size`
+pub async fn fetch(url: &str, max_size: usize, timeout: Duration) -> Result<Vec<u8>, Error> {
+    let url = reqwest::Url::parse(url)
+        .map_err(|e| Error::BadRequest(format!("Invalid URL {}: {}", url, e)))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(Error::BadRequest(format!("Unsupported URL {}", url)));
+    }
+
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+    let mut res = client.get(url.clone()).send().await?.error_for_status()?;
+
+    if res
+        .content_length()
+        .is_some_and(|len| len > max_size as u64)
+    {
+        return Err(Error::UploadTooLarge(max_size));
+    }
+
+    let mut content = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        if content.len() + chunk.len() > max_size {
+            return Err(Error::UploadTooLarge(max_size));
+        }
+        content.extend_from_slice(&chunk);
+    }
+
+    Ok(content)
+}
+
+/// The last path segment of a URL, to be used as the original name of a fetched image
+pub fn url_filename(url: &str) -> Option<String> {
+    reqwest::Url::parse(url)
+        .ok()?
+        .path_segments()?
+        .next_back()
+        .and_then(sanitize_filename)
+}
+
+/// Reads a multipart field into memory, failing if it grows past `max_size`
+pub async fn read_field(field: &mut Field, max_size: usize) -> Result<Vec<u8>, Error> {
+    let mut content = Vec::new();
+
+    let mut too_large = false;
+
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk?;
+        // Keep draining the field so that the rest of the body can still be read
+        too_large |= content.len() + chunk.len() > max_size;
+        if !too_large {
+            content.extend_from_slice(&chunk);
+        }
+    }
+
+    if too_large {
+        return Err(Error::UploadTooLarge(max_size));
+    }
+
+    Ok(content)
+}
+
+/// Checks that `content` is an image of a supported type with sensible dimensions
+pub fn validate(content: &[u8]) -> Result<ImageKind, Error> {
+    if content.is_empty() {
+        return Err(Error::BadRequest("No file was uploaded".to_string()));
+    }
+
+    let kind = ImageKind::sniff(content).ok_or(Error::UnsupportedMediaType)?;
+
+    let size = imagesize::blob_size(content)
+        .map_err(|e| Error::InvalidImage(format!("Could not read the dimensions: {}", e)))?;
+
+    if size.width == 0 || size.height == 0 {
+        return Err(Error::InvalidImage("The image is empty".to_string()));
+    }
+
+    if size.width > MAX_DIMENSION
+        || size.height > MAX_DIMENSION
+        || size.width * size.height > MAX_PIXELS
+    {
+        return Err(Error::InvalidImage(format!(
+            "The image is too large ({}x{})",
+            size.width, size.height
+        )));
+    }
+
+    Ok(kind)
+}
+
+pub fn content_hash(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+/// The name an upload is stored under, derived from its content so identical images share a file
+pub fn content_name(content: &[u8], kind: ImageKind) -> String {
+    format!("{}.{}", content_hash(content), kind.extension())
+}
+
+/// Reduces a client supplied filename to something safe to keep around as metadata
+pub fn sanitize_filename(filename: &str) -> Option<String> {
+    let name = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(255)
+        .collect::<String>();
+    let name = name.trim();
+
+    if name.is_empty() || name == "." || name == ".." {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// Album names end up in URLs, so they are limited to lowercase letters, digits, dashes and underscores
+pub fn sanitize_album(album: &str) -> Result<Option<String>, Error> {
+    let album = album.trim().to_lowercase().replace(' ', "-");
+
+    if album.is_empty() {
+        Ok(None)
+    } else if album == "map" {
+        // Taken by the map view of the gallery
+        Err(Error::BadRequest(format!("Reserved album name {}", album)))
+    } else if album.len() <= 64
+        && album
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(Some(album))
+    } else {
+        Err(Error::BadRequest(format!("Invalid album name {}", album)))
+    }
+}
+
+/// Sets one of the location fields of an upload from the text sent for it
+pub fn set_location_field(location: &mut Location, name: &str, value: &str) -> Result<(), Error> {
+    let invalid = || Error::BadRequest(format!("Invalid {} {}", name, value));
+
+    if value.is_empty() {
+        return Ok(());
+    }
+
+    match name {
+        "latitude" => location.latitude = Some(value.parse().map_err(|_| invalid())?),
+        "longitude" => location.longitude = Some(value.parse().map_err(|_| invalid())?),
+        "round" => location.round = Some(value.parse().map_err(|_| invalid())?),
+        "country" => location.country = sanitize_country(value)?,
+        "game" => location.game = sanitize_game(value)?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Checks that the coordinates are on the globe and are either both set or both unset
+pub fn validate_location(
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    round: Option<i32>,
+) -> Result<(), Error> {
+    match (latitude, longitude) {
+        (Some(lat), Some(lng)) => {
+            if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lng) {
+                return Err(Error::BadRequest(format!(
+                    "Invalid coordinates {}, {}",
+                    lat, lng
+                )));
+            }
+        }
+        (None, None) => {}
+        _ => {
+            return Err(Error::BadRequest(
+                "Both the latitude and the longitude have to be given".to_string(),
+            ))
+        }
+    }
+
+    if round.is_some_and(|r| r < 1) {
+        return Err(Error::BadRequest("Rounds are numbered from 1".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Countries are stored as uppercase ISO 3166-1 alpha-2 codes so that they can be filtered by
+pub fn sanitize_country(country: &str) -> Result<Option<String>, Error> {
+    let country = country.trim().to_uppercase();
+
+    if country.is_empty() {
+        Ok(None)
+    } else if country.len() == 2 && country.chars().all(|c| c.is_ascii_uppercase()) {
+        Ok(Some(country))
+    } else {
+        Err(Error::BadRequest(format!(
+            "Invalid country {}, expected a two letter country code",
+            country
+        )))
+    }
+}
+
+/// Accepts either a GeoGuessr game token or a link to the game, keeping only the token
+pub fn sanitize_game(game: &str) -> Result<Option<String>, Error> {
+    let game = game
+        .trim()
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+
+    if game.is_empty() {
+        Ok(None)
+    } else if game.len() <= 64
+        && game
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(Some(game.to_string()))
+    } else {
+        Err(Error::BadRequest(format!("Invalid game {}", game)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    /// Answers a single request with `response` after waiting for `delay`
+    fn serve(response: Vec<u8>, delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            std::thread::sleep(delay);
+            let _ = stream.write_all(&response);
+        });
+
+        format!("http://{}/image.png", address)
+    }
+
+    fn response(body: &[u8], content_length: bool) -> Vec<u8> {
+        let mut response = b"HTTP/1.1 200 OK\r\nConnection: close\r\n".to_vec();
+        if content_length {
+            response.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+        }
+        response.extend_from_slice(b"\r\n");
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[actix_web::test]
+    async fn fetch_downloads_the_body() {
+        let url = serve(response(PNG, true), Duration::ZERO);
+        let content = fetch(&url, 1024, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(content, PNG);
+    }
+
+    #[actix_web::test]
+    async fn fetch_rejects_a_large_content_length() {
+        let url = serve(response(&[0; 2048], true), Duration::ZERO);
+        let res = fetch(&url, 1024, Duration::from_secs(5)).await;
+        assert!(matches!(res, Err(Error::UploadTooLarge(1024))));
+    }
+
+    #[actix_web::test]
+    async fn fetch_rejects_a_large_body_without_content_length() {
+        let url = serve(response(&[0; 2048], false), Duration::ZERO);
+        let res = fetch(&url, 1024, Duration::from_secs(5)).await;
+        assert!(matches!(res, Err(Error::UploadTooLarge(1024))));
+    }
+
+    #[actix_web::test]
+    async fn fetch_gives_up_on_a_stalled_server() {
+        let url = serve(response(PNG, true), Duration::from_secs(5));
+        let res = fetch(&url, 1024, Duration::from_millis(200)).await;
+        assert!(matches!(res, Err(Error::Fetch(e)) if e.is_timeout()));
+    }
+
+    #[actix_web::test]
+    async fn fetch_rejects_other_schemes() {
+        for url in ["file:///etc/passwd", "ftp://localhost/image.png"] {
+            let res = fetch(url, 1024, Duration::from_secs(5)).await;
+            assert!(matches!(res, Err(Error::BadRequest(_))));
+        }
+    }
+
+    #[test]
+    fn sniff_detects_supported_formats() {
+        let cases: [(&[u8], ImageKind); 5] = [
+            (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", ImageKind::Png),
+            (b"\xff\xd8\xff\xe0\0\x10JFIF", ImageKind::Jpeg),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", ImageKind::Webp),
+            (b"GIF87a\x01\0\x01\0", ImageKind::Gif),
+            (b"GIF89a\x01\0\x01\0", ImageKind::Gif),
+        ];
+        for (content, kind) in cases {
+            assert_eq!(ImageKind::sniff(content), Some(kind));
+        }
+    }
+
+    #[test]
+    fn sniff_rejects_truncated_input() {
+        for content in [
+            &b""[..],
+            b"\x89PNG\r\n\x1a",
+            b"\xff\xd8",
+            b"RIFF\x24\0\0\0WEB",
+            b"GIF89",
+        ] {
+            assert_eq!(ImageKind::sniff(content), None);
+        }
+    }
+
+    #[test]
+    fn sniff_rejects_other_files() {
+        for content in [
+            &b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"[..],
+            b"%PDF-1.7\n",
+            b"RIFF\x24\0\0\0WAVEfmt ",
+            b"\0\0\0\x1cftypavif\0\0\0\0",
+            b"\0\0\0\x18ftypmp42\0\0\0\0",
+            b"GIF90a",
+        ] {
+            assert_eq!(ImageKind::sniff(content), None);
+        }
+    }
+}
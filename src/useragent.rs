@@ -0,0 +1,44 @@
+/// Substrings identifying browsers, checked in order since most user agents
+/// also claim to be the browsers they were derived from
+const BROWSERS: [(&str, &str); 9] = [
+    ("Edg/", "Edge"),
+    ("OPR/", "Opera"),
+    ("Opera", "Opera"),
+    ("SamsungBrowser/", "Samsung Internet"),
+    ("Firefox/", "Firefox"),
+    ("FxiOS/", "Firefox"),
+    ("CriOS/", "Chrome"),
+    ("Chrome/", "Chrome"),
+    ("Safari/", "Safari"),
+];
+
+/// The longest user agent that is stored, anything longer is truncated
+pub const MAX_LENGTH: usize = 512;
+
+/// The name of the browser a user agent belongs to
+pub fn browser(user_agent: Option<&str>) -> &'static str {
+    let Some(user_agent) = user_agent.filter(|ua| !ua.is_empty()) else {
+        return "Unknown";
+    };
+
+    if crate::bots::is_bot_agent(user_agent) {
+        return "Bot";
+    }
+
+    BROWSERS
+        .iter()
+        .find(|(signature, _)| user_agent.contains(signature))
+        .map(|(_, name)| *name)
+        .unwrap_or("Other")
+}
+
+/// The host of the referring page, unless it is the site itself
+pub fn referrer_host(referrer: &str, own_host: &str) -> Option<String> {
+    let host = reqwest::Url::parse(referrer)
+        .ok()?
+        .host_str()?
+        .to_lowercase();
+    let own_host = own_host.split(':').next().unwrap_or_default();
+
+    (host != own_host.to_lowercase()).then_some(host)
+}
@@ -1,12 +1,26 @@
+use crate::bots::BotDetector;
 use crate::database::Database;
+use crate::geoip::GeoIp;
+use crate::live::LiveVisitors;
+use crate::useragent;
 
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime};
+use rand::Rng;
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
 use std::future::{ready, Ready};
+use std::net::{IpAddr, SocketAddr};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error,
+    http::{header, Uri},
+    web, Error,
 };
 use futures_util::future::LocalBoxFuture;
 
@@ -16,10 +30,323 @@ pub struct Visit {
     visitor: String,
     path: String,
     instance: NaiveDateTime,
+    /// The host of the referring page, left out for links within the site
+    referrer: Option<String>,
+    user_agent: Option<String>,
+    status: i32,
+    /// How long it took to produce the response, not including sending the body
+    latency_ms: i32,
+    is_bot: bool,
+    #[diesel(embed)]
+    campaign: Campaign,
+    /// The ISO 3166-1 code of the country the visitor's address is in
+    country: Option<String>,
+}
+
+/// The address may come from a forwarding header, which can include the port
+fn parse_address(address: &str) -> Option<IpAddr> {
+    address
+        .parse::<IpAddr>()
+        .or_else(|_| address.parse::<SocketAddr>().map(|a| a.ip()))
+        .ok()
+}
+
+/// Query parameters added by analytics and advertising platforms, which do not change the page.
+/// Every parameter starting with `utm_` is left out as well.
+const TRACKING_PARAMETERS: [&str; 15] = [
+    "fbclid", "gclid", "dclid", "gbraid", "wbraid", "msclkid", "yclid", "twclid", "ttclid",
+    "igshid", "mc_cid", "mc_eid", "_ga", "_gl", "ref",
+];
+
+/// The longest UTM value that is stored, anything longer is truncated
+const MAX_CAMPAIGN_LENGTH: usize = 128;
+
+/// Where a visitor came from according to the UTM parameters of the link they followed
+#[derive(diesel::Insertable, Default, Debug, PartialEq, Eq)]
+#[diesel(table_name = crate::schema::visits)]
+pub struct Campaign {
+    utm_source: Option<String>,
+    utm_medium: Option<String>,
+    utm_campaign: Option<String>,
+}
+
+/// The path a visit is counted for, so that `/blog/x200`, `/blog/x200.html`, `/blog/x200/` and
+/// `/blog/x200?utm_source=...` are all the same page, along with the campaign of the visit
+pub fn canonical_path(uri: &Uri) -> (String, Campaign) {
+    let path = uri.path().trim_end_matches('/');
+    let path = path.strip_suffix(".html").unwrap_or(path);
+    let mut path = match path {
+        "" | "/index" => "/".to_string(),
+        path => path.to_string(),
+    };
+
+    let Some(query) = uri.query() else {
+        return (path, Campaign::default());
+    };
+
+    let params = query
+        .split('&')
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or_default();
+            !name.is_empty() && !name.starts_with("utm_") && !TRACKING_PARAMETERS.contains(&name)
+        })
+        .collect::<Vec<_>>();
+    if !params.is_empty() {
+        path.push('?');
+        path.push_str(&params.join("&"));
+    }
+
+    let values = web::Query::<std::collections::HashMap<String, String>>::from_query(query)
+        .map(|q| q.into_inner())
+        .unwrap_or_default();
+    let value = |name| {
+        values
+            .get(name)
+            .filter(|v| !v.is_empty())
+            .map(|v| v.chars().take(MAX_CAMPAIGN_LENGTH).collect())
+    };
+    let campaign = Campaign {
+        utm_source: value("utm_source"),
+        utm_medium: value("utm_medium"),
+        utm_campaign: value("utm_campaign"),
+    };
+
+    (path, campaign)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisitorMode {
+    /// Visitors are stored as their address hashed with a salt that changes daily
+    Hashed,
+    /// Visitors are stored as their address
+    Raw,
+}
+
+/// Turns the addresses of visitors into what is stored in the visits table
+pub struct Visitors {
+    mode: VisitorMode,
+    salt: Mutex<Option<(NaiveDate, String)>>,
+}
+
+impl Visitors {
+    pub fn from_env() -> Self {
+        let mode = match std::env::var("VISITOR_MODE").as_deref() {
+            Ok("raw") => VisitorMode::Raw,
+            Ok("hashed") | Err(_) => VisitorMode::Hashed,
+            Ok(mode) => panic!("Unknown VISITOR_MODE {}, expected hashed or raw", mode),
+        };
+
+        Self {
+            mode,
+            salt: Mutex::new(None),
+        }
+    }
+
+    pub async fn identify(
+        &self,
+        db: &Database,
+        address: &str,
+    ) -> Result<String, crate::error::Error> {
+        if self.mode == VisitorMode::Raw {
+            return Ok(address.to_string());
+        }
+
+        let today = chrono::Local::now().date_naive();
+        let cached = self
+            .salt
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|(day, _)| *day == today);
+
+        // The salt is kept in the database so that restarts during the day keep the same visitors
+        let salt = match cached {
+            Some((_, salt)) => salt,
+            None => {
+                let salt = db
+                    .visitor_salt(today, hex::encode(rand::thread_rng().gen::<[u8; 32]>()))
+                    .await?;
+                *self.salt.lock().unwrap() = Some((today, salt.clone()));
+                salt
+            }
+        };
+
+        Ok(hex::encode(Sha256::digest(format!("{}{}", salt, address))))
+    }
+}
+
+/// How many days visits are kept before they are rolled up into daily totals, which leaves out
+/// their referrers, user agents and campaigns. Every visit is kept if `VISIT_RETENTION_DAYS` is not set.
+pub static RETENTION_DAYS: LazyLock<Option<i64>> = LazyLock::new(|| {
+    std::env::var("VISIT_RETENTION_DAYS").ok().map(|days| {
+        days.parse()
+            .ok()
+            .filter(|days| *days > 0)
+            .expect("VISIT_RETENTION_DAYS is not a positive number")
+    })
+});
+/// How often the visits past the retention period are rolled up
+const ROLL_UP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Rolls up the visits past the retention period, if there is one, every `ROLL_UP_INTERVAL`
+pub fn spawn_roll_up(db: Database) {
+    let Some(days) = *RETENTION_DAYS else {
+        return;
+    };
+
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(ROLL_UP_INTERVAL);
+        loop {
+            interval.tick().await;
+            // Only whole days are rolled up so that no day is split between the tables
+            let before = (chrono::Local::now().date_naive() - chrono::Duration::days(days))
+                .and_time(chrono::NaiveTime::MIN);
+            let db = db.clone();
+            match web::block(move || db.roll_up_visits(before)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(rolled)) => log::info!("Rolled up {} visits from before {}", rolled, before),
+                Ok(Err(e)) => log::error!("Failed to roll up visits: {}", e),
+                Err(e) => log::error!("Failed to roll up visits: {}", e),
+            }
+        }
+    });
+}
+
+/// The number of visits that may wait to be stored before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+/// The most visits stored with a single insert
+const BATCH_SIZE: usize = 256;
+/// How long a visit may wait before it is stored
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicU64,
+    recorded: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct RecorderMetrics {
+    /// Visits stored in the database
+    pub recorded: u64,
+    /// Visits dropped because too many were already waiting to be stored
+    pub dropped: u64,
+    /// Visits lost because storing them failed
+    pub failed: u64,
+    /// Visits waiting to be stored
+    pub queued: u64,
+}
+
+/// Stores visits in batches in the background, so that responses never wait for the database
+#[derive(Clone)]
+pub struct VisitRecorder {
+    sender: mpsc::Sender<Visit>,
+    counters: Arc<Counters>,
+}
+
+impl VisitRecorder {
+    /// Starts the task storing the visits. It finishes once every recorder has been dropped
+    /// and the visits still waiting have been stored.
+    pub fn start(db: Database) -> (Self, JoinHandle<()>) {
+        Self::start_with(move |batch| db.new_visits(batch))
+    }
+
+    /// Like `start`, storing each batch with `store` on the blocking threads
+    fn start_with<F>(store: F) -> (Self, JoinHandle<()>)
+    where
+        F: Fn(&[Visit]) -> Result<usize, crate::error::Error> + Clone + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let counters = Arc::new(Counters::default());
+        let task = actix_rt::spawn(store_visits(store, receiver, Arc::clone(&counters)));
+
+        (Self { sender, counters }, task)
+    }
+
+    fn record(&self, visit: Visit) {
+        match self.sender.try_send(visit) {
+            Ok(()) => {
+                self.counters.queued.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                let dropped = self.counters.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped % 1000 == 1 {
+                    log::warn!("The visit queue is full, {} visits dropped so far", dropped);
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+
+    pub fn metrics(&self) -> RecorderMetrics {
+        RecorderMetrics {
+            recorded: self.counters.recorded.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+            queued: self.counters.queued.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Waits for a visit, then collects more until the batch is full or it has waited for
+/// `FLUSH_INTERVAL`, and stores them all with one insert
+async fn store_visits<F>(store: F, mut receiver: mpsc::Receiver<Visit>, counters: Arc<Counters>)
+where
+    F: Fn(&[Visit]) -> Result<usize, crate::error::Error> + Clone + Send + 'static,
+{
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+    while let Some(visit) = receiver.recv().await {
+        batch.push(visit);
+        let deadline = tokio::time::Instant::now() + FLUSH_INTERVAL;
+        while batch.len() < BATCH_SIZE {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(visit)) => batch.push(visit),
+                // Either the time is up or the recorders are gone, in which case the outer loop ends
+                _ => break,
+            }
+        }
+
+        let len = batch.len();
+        let store = store.clone();
+        let stored = web::block(move || {
+            let stored = store(&batch);
+            (batch, stored)
+        })
+        .await;
+
+        match stored {
+            Ok((used, Ok(stored))) => {
+                counters
+                    .recorded
+                    .fetch_add(stored as u64, Ordering::Relaxed);
+                batch = used;
+            }
+            Ok((used, Err(e))) => {
+                log::error!("Failed to store {} visits: {}", len, e);
+                counters.failed.fetch_add(len as u64, Ordering::Relaxed);
+                batch = used;
+            }
+            Err(e) => {
+                log::error!("Failed to store {} visits: {}", len, e);
+                counters.failed.fetch_add(len as u64, Ordering::Relaxed);
+                batch = Vec::with_capacity(BATCH_SIZE);
+            }
+        }
+        counters.queued.fetch_sub(len as u64, Ordering::Relaxed);
+        batch.clear();
+    }
 }
 
 pub struct VisitCounter {
     pub db: Database,
+    pub visitors: Arc<Visitors>,
+    pub bots: Arc<BotDetector>,
+    pub recorder: VisitRecorder,
+    pub live: Arc<LiveVisitors>,
+    pub geoip: Arc<GeoIp>,
 }
 
 impl<S, B> Transform<S, ServiceRequest> for VisitCounter
@@ -38,6 +365,11 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(VisitCounterMiddleware {
             db: self.db.clone(),
+            visitors: Arc::clone(&self.visitors),
+            bots: Arc::clone(&self.bots),
+            recorder: self.recorder.clone(),
+            live: Arc::clone(&self.live),
+            geoip: Arc::clone(&self.geoip),
             service: Rc::new(service),
         }))
     }
@@ -45,6 +377,11 @@ where
 
 pub struct VisitCounterMiddleware<S> {
     db: Database,
+    visitors: Arc<Visitors>,
+    bots: Arc<BotDetector>,
+    recorder: VisitRecorder,
+    live: Arc<LiveVisitors>,
+    geoip: Arc<GeoIp>,
     service: Rc<S>,
 }
 
@@ -64,19 +401,57 @@ where
         let head = req.head();
         let service = Rc::new(self.service.clone());
         let db_clone = self.db.clone();
+        let visitors = Arc::clone(&self.visitors);
+        let recorder = self.recorder.clone();
+        let live = Arc::clone(&self.live);
         let connection_info = req.connection_info().clone();
 
         if let Some(address) = connection_info.realip_remote_addr() {
-            let visit = Visit {
-                visitor: address.to_string(),
-                instance: chrono::Local::now().naive_local(),
-                path: head.uri.to_string(),
+            let address = address.to_string();
+            let instance = chrono::Local::now().naive_local();
+            let started = Instant::now();
+            let (path, campaign) = canonical_path(&head.uri);
+
+            let header = |name| {
+                head.headers
+                    .get(name)
+                    .and_then(|h| h.to_str().ok())
+                    .map(String::from)
             };
+            let referrer = header(header::REFERER)
+                .and_then(|r| useragent::referrer_host(&r, connection_info.host()));
+            let user_agent = header(header::USER_AGENT)
+                .filter(|ua| !ua.is_empty())
+                .map(|ua| ua.chars().take(useragent::MAX_LENGTH).collect());
+            let ip = parse_address(&address);
+            let is_bot = self.bots.is_bot(
+                user_agent.as_deref(),
+                header(header::ACCEPT_LANGUAGE).as_deref(),
+                ip,
+            );
+            // Only the country is kept, never anything more precise
+            let country = ip.and_then(|ip| self.geoip.country(ip));
 
             Box::pin(async move {
                 let res = service.call(req).await?;
-                if res.status().is_success() {
-                    let _ = db_clone.new_visit(visit).await;
+                // Every response is recorded, the stats only count the successful ones
+                if let Ok(visitor) = visitors.identify(&db_clone, &address).await {
+                    if !is_bot && res.status().is_success() {
+                        live.record(&visitor, &path);
+                    }
+                    let visit = Visit {
+                        visitor,
+                        path,
+                        instance,
+                        referrer,
+                        user_agent,
+                        status: res.status().as_u16() as i32,
+                        latency_ms: started.elapsed().as_millis() as i32,
+                        is_bot,
+                        campaign,
+                        country,
+                    };
+                    recorder.record(visit);
                 }
 
                 Ok(res)
@@ -86,3 +461,180 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonical(uri: &str) -> (String, Campaign) {
+        canonical_path(&uri.parse().unwrap())
+    }
+
+    fn path(uri: &str) -> String {
+        canonical(uri).0
+    }
+
+    #[test]
+    fn html_is_stripped() {
+        assert_eq!(path("/blog/x200.html"), "/blog/x200");
+        assert_eq!(path("/index.html"), "/");
+        assert_eq!(path("/index"), "/");
+        assert_eq!(path("/blog/index"), "/blog/index");
+    }
+
+    #[test]
+    fn trailing_slashes_are_stripped() {
+        assert_eq!(path("/"), "/");
+        assert_eq!(path("/blog/x200/"), "/blog/x200");
+        assert_eq!(path("/blog//"), "/blog");
+        assert_eq!(path("/blog/x200.html/"), "/blog/x200");
+    }
+
+    #[test]
+    fn tracking_parameters_are_removed() {
+        assert_eq!(path("/blog?utm_source=a&utm_medium=b"), "/blog");
+        assert_eq!(path("/blog?utm_content=x&utm_term=y"), "/blog");
+        for name in TRACKING_PARAMETERS {
+            assert_eq!(path(&format!("/blog/?{}=abc", name)), "/blog");
+        }
+        assert_eq!(path("/blog?&=x&"), "/blog");
+    }
+
+    #[test]
+    fn other_parameters_are_kept() {
+        assert_eq!(path("/stats?path=/blog"), "/stats?path=/blog");
+        assert_eq!(
+            path("/gallery/?album=a&fbclid=x&page=2&utm_source=y"),
+            "/gallery?album=a&page=2"
+        );
+        assert_eq!(path("/blog?reference=1"), "/blog?reference=1");
+    }
+
+    #[test]
+    fn campaign_is_extracted() {
+        let (path, campaign) =
+            canonical("/blog.html?utm_source=mastodon&utm_medium=social&utm_campaign=x%20200");
+        assert_eq!(path, "/blog");
+        assert_eq!(
+            campaign,
+            Campaign {
+                utm_source: Some("mastodon".to_string()),
+                utm_medium: Some("social".to_string()),
+                utm_campaign: Some("x 200".to_string()),
+            }
+        );
+
+        assert_eq!(canonical("/blog?utm_source=&page=1").1, Campaign::default());
+        assert_eq!(
+            canonical(&format!("/?utm_campaign={}", "a".repeat(200)))
+                .1
+                .utm_campaign
+                .map(|c| c.len()),
+            Some(MAX_CAMPAIGN_LENGTH)
+        );
+    }
+
+    #[test]
+    fn migration_has_every_tracking_parameter() {
+        let migration =
+            include_str!("../migrations/2023-08-26-090000_visits_canonical_paths/up.sql");
+        let list = migration
+            .split_once("NOT IN (")
+            .and_then(|(_, rest)| rest.split_once(')'))
+            .map(|(list, _)| list)
+            .unwrap();
+        let names = list
+            .split(',')
+            .map(|name| name.trim().trim_matches('\''))
+            .collect::<Vec<_>>();
+        assert_eq!(names, TRACKING_PARAMETERS);
+    }
+
+    fn visit() -> Visit {
+        Visit {
+            visitor: "visitor".to_string(),
+            path: "/".to_string(),
+            instance: chrono::Local::now().naive_local(),
+            referrer: None,
+            user_agent: None,
+            status: 200,
+            latency_ms: 1,
+            is_bot: false,
+            campaign: Campaign::default(),
+            country: None,
+        }
+    }
+
+    /// Starts a recorder that remembers the size of every batch it stores
+    fn recorder() -> (VisitRecorder, JoinHandle<()>, Arc<Mutex<Vec<usize>>>) {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let stored = Arc::clone(&batches);
+        let (recorder, task) = VisitRecorder::start_with(move |batch: &[Visit]| {
+            stored.lock().unwrap().push(batch.len());
+            Ok(batch.len())
+        });
+        (recorder, task, batches)
+    }
+
+    #[actix_web::test]
+    async fn visits_are_stored_in_batches() {
+        let (recorder, task, batches) = recorder();
+        for _ in 0..600 {
+            recorder.record(visit());
+        }
+        let counters = Arc::clone(&recorder.counters);
+        drop(recorder);
+        task.await.unwrap();
+
+        assert_eq!(*batches.lock().unwrap(), [256, 256, 88]);
+        assert_eq!(counters.recorded.load(Ordering::Relaxed), 600);
+        assert_eq!(counters.queued.load(Ordering::Relaxed), 0);
+    }
+
+    #[actix_web::test]
+    async fn visits_are_dropped_when_the_queue_is_full() {
+        let (recorder, task, _) = recorder();
+        for _ in 0..QUEUE_SIZE + 10 {
+            recorder.record(visit());
+        }
+        let metrics = recorder.metrics();
+        assert_eq!(metrics.dropped, 10);
+        assert_eq!(metrics.queued, QUEUE_SIZE as u64);
+
+        let counters = Arc::clone(&recorder.counters);
+        drop(recorder);
+        task.await.unwrap();
+        assert_eq!(counters.recorded.load(Ordering::Relaxed), QUEUE_SIZE as u64);
+    }
+
+    #[actix_web::test]
+    async fn waiting_visits_are_stored_when_the_recorders_are_dropped() {
+        let (recorder, task, batches) = recorder();
+        for _ in 0..3 {
+            recorder.record(visit());
+        }
+        let started = Instant::now();
+        drop(recorder);
+        task.await.unwrap();
+
+        assert!(started.elapsed() < FLUSH_INTERVAL);
+        assert_eq!(*batches.lock().unwrap(), [3]);
+    }
+
+    #[actix_web::test]
+    async fn failed_batches_are_counted() {
+        let (recorder, task) = VisitRecorder::start_with(|_: &[Visit]| {
+            Err(crate::error::Error::BadRequest("failed".to_string()))
+        });
+        for _ in 0..5 {
+            recorder.record(visit());
+        }
+        let counters = Arc::clone(&recorder.counters);
+        drop(recorder);
+        task.await.unwrap();
+
+        assert_eq!(counters.failed.load(Ordering::Relaxed), 5);
+        assert_eq!(counters.recorded.load(Ordering::Relaxed), 0);
+        assert_eq!(counters.queued.load(Ordering::Relaxed), 0);
+    }
+}
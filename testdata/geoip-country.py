@@ -0,0 +1,136 @@
+"""Generates the small MaxMind DB file used by the tests of src/geoip.rs.
+
+The file follows the MaxMind DB format 2.0 (https://maxmind.github.io/MaxMind-DB/)
+with an IPv6 search tree of 24 bit records, so it can be regenerated without any
+MaxMind tooling:
+
+    python3 testdata/geoip-country.py testdata/geoip-country.mmdb
+"""
+
+import ipaddress
+import struct
+import sys
+
+NETWORKS = [
+    ("10.1.0.0/16", {
+        "continent": {"code": "EU"},
+        "country": {
+            "geoname_id": 660013,
+            "is_in_european_union": True,
+            "iso_code": "FI",
+            "names": {"en": "Finland"},
+        },
+        "location": {"latitude": 60.1},
+    }),
+    ("2001:db8::/32", {"country": {"iso_code": "SE"}}),
+    ("192.0.2.0/24", {"registered_country": {"iso_code": "DE"}}),
+    ("198.51.100.0/24", {"country": {"iso_code": "x1"}}),
+]
+
+
+def control(kind, size):
+    first = kind << 5 if kind <= 7 else 0
+    if size < 29:
+        first |= size
+        extra = b""
+    elif size < 285:
+        first |= 29
+        extra = bytes([size - 29])
+    elif size < 65821:
+        first |= 30
+        extra = struct.pack(">H", size - 285)
+    else:
+        first |= 31
+        extra = struct.pack(">I", size - 65821)[1:]
+    out = bytes([first])
+    if kind > 7:
+        out += bytes([kind - 7])
+    return out + extra
+
+
+def pointer(offset):
+    if offset < 2048:
+        return bytes([0x20 | (offset >> 8), offset & 0xFF])
+    offset -= 2048
+    return bytes([0x28 | (offset >> 16), (offset >> 8) & 0xFF, offset & 0xFF])
+
+
+def encode(value, pointers=None):
+    if isinstance(value, str):
+        if pointers is not None and value in pointers:
+            return pointer(pointers[value])
+        raw = value.encode()
+        return control(2, len(raw)) + raw
+    if isinstance(value, bool):
+        return control(14, int(value))
+    if isinstance(value, int):
+        raw = value.to_bytes((value.bit_length() + 7) // 8, "big") if value else b""
+        return control(6 if value < 2**32 else 9, len(raw)) + raw
+    if isinstance(value, float):
+        return control(3, 8) + struct.pack(">d", value)
+    if isinstance(value, list):
+        return control(11, len(value)) + b"".join(encode(v, pointers) for v in value)
+    if isinstance(value, dict):
+        return control(7, len(value)) + b"".join(
+            encode(k, pointers) + encode(v, pointers) for k, v in value.items()
+        )
+    raise TypeError(value)
+
+
+def build(networks):
+    # The records point to a shared "iso_code" key at the start of the data section
+    data = encode("iso_code")
+    pointers = {"iso_code": 0}
+    offsets = []
+    for _, record in networks:
+        offsets.append(len(data))
+        data += encode(record, pointers)
+
+    # A binary trie over 128 bit addresses, with IPv4 networks in ::/96
+    nodes = [[None, None]]
+    for i, (network, _) in enumerate(networks):
+        network = ipaddress.ip_network(network)
+        bits = int(network.network_address)
+        prefix = network.prefixlen + (96 if network.version == 4 else 0)
+        node = 0
+        for depth in range(prefix):
+            bit = (bits >> (127 - depth)) & 1
+            if depth == prefix - 1:
+                nodes[node][bit] = ("data", i)
+            else:
+                if nodes[node][bit] is None:
+                    nodes.append([None, None])
+                    nodes[node][bit] = ("node", len(nodes) - 1)
+                node = nodes[node][bit][1]
+
+    count = len(nodes)
+
+    def record(value):
+        if value is None:
+            return count
+        if value[0] == "node":
+            return value[1]
+        return count + 16 + offsets[value[1]]
+
+    tree = b"".join(
+        record(left).to_bytes(3, "big") + record(right).to_bytes(3, "big")
+        for left, right in nodes
+    )
+    metadata = {
+        "binary_format_major_version": 2,
+        "binary_format_minor_version": 0,
+        "build_epoch": 1,
+        "database_type": "Test-Country",
+        "description": {"en": "test"},
+        "ip_version": 6,
+        "languages": ["en"],
+        "node_count": count,
+        "record_size": 24,
+    }
+    return tree + b"\0" * 16 + data + b"\xab\xcd\xefMaxMind.com" + encode(metadata)
+
+
+if __name__ == "__main__":
+    path = sys.argv[1] if len(sys.argv) > 1 else "testdata/geoip-country.mmdb"
+    with open(path, "wb") as f:
+        f.write(build(NETWORKS))